@@ -1,10 +1,14 @@
-use std::path::Path;
-
 use which::which;
 
-use super::log_execute;
+use super::{log_execute, migrate::Backend};
+
+pub fn check_and_install_diesel_cli(backend: Backend) {
+    // Bundled, no SQLite library is needed on the system
+    let backend = match backend {
+        Backend::Sqlite => "sqlite-bundled",
+        Backend::Postgres => "postgres",
+    };
 
-pub fn check_and_install_diesel_cli() {
     if which("diesel").is_err() {
         log_execute(
             "Installing diesel-cli",
@@ -14,27 +18,13 @@ pub fn check_and_install_diesel_cli() {
                 "diesel_cli",
                 "--no-default-features",
                 "--features",
-                "postgres",
+                backend,
                 "-q",
                 "--color",
                 "always",
             ],
         );
     }
-
-    if !Path::new("diesel.toml").exists()
-        || !Path::new("migrations").exists()
-        || !Path::new("src/schema.rs").exists()
-    {
-        log_execute("Diesel setup", "diesel", &["setup"]);
-        log_execute("Reset database", "diesel", &["database", "reset"]);
-        log_execute("Migrating database", "diesel", &["migration", "run"]);
-        log_execute(
-            "Patching schema",
-            "sed",
-            &["-i", "s/^diesel::/crate::diesel::/g", "src/schema.rs"],
-        );
-    }
 }
 
 pub fn check_and_install_wasm_pack() {
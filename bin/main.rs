@@ -2,6 +2,7 @@ use clap::{App, Arg, SubCommand};
 
 mod install;
 mod logger;
+mod migrate;
 mod new;
 mod print;
 
@@ -15,6 +16,29 @@ fn main() {
         .about("Isomorphic web framework for Rust")
         .subcommand(SubCommand::with_name("build").about("Build the current project directory"))
         .subcommand(SubCommand::with_name("run").about("Run the current project directory"))
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Manage the database of the current project directory")
+                .subcommand(
+                    SubCommand::with_name("migrate")
+                        .about("Generate a migration for the changed models, and run it")
+                        .arg(
+                            Arg::with_name("dry-run")
+                                .long("dry-run")
+                                .help("Only print the generated migration"),
+                        )
+                        .arg(Arg::with_name("sqlite").long("sqlite").help(
+                            "Generate a SQLite migration, the default when DATABASE_URL isn't a postgres:// URL",
+                        )),
+                )
+                .subcommand(
+                    SubCommand::with_name("reset")
+                        .about("Drop the database and run every migration again")
+                        .arg(Arg::with_name("sqlite").long("sqlite").help(
+                            "Reset a SQLite database, the default when DATABASE_URL isn't a postgres:// URL",
+                        )),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("new")
                 .about("Create a new empty project folder")
@@ -32,6 +56,20 @@ fn main() {
         build();
     } else if let Some(_matches) = matches.subcommand_matches("run") {
         run();
+    } else if let Some(matches) = matches.subcommand_matches("db") {
+        if let Some(matches) = matches.subcommand_matches("migrate") {
+            let backend = migrate::Backend::new(matches.is_present("sqlite"));
+
+            check_and_install_diesel_cli(backend);
+
+            migrate::migrate(matches.is_present("dry-run"), backend);
+        } else if let Some(matches) = matches.subcommand_matches("reset") {
+            check_and_install_diesel_cli(migrate::Backend::new(matches.is_present("sqlite")));
+
+            migrate::reset();
+        } else {
+            println!("{}", matches.usage());
+        }
     } else if let Some(matches) = matches.subcommand_matches("new") {
         create_project_folder(matches.value_of("name").unwrap());
     } else {
@@ -39,9 +77,9 @@ fn main() {
     }
 }
 
+// The migrations are only generated and run by `comet db migrate`
 fn build() {
     check_and_install_wasm_pack();
-    check_and_install_diesel_cli();
 
     log_execute(
         "Building client",
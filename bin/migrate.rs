@@ -0,0 +1,1089 @@
+use std::{env, fs, path::Path};
+
+use super::log_execute;
+
+#[path = "../macros/macro_procs/src/schema.rs"]
+mod schema;
+
+use schema::{Column, CustomTypes, Table, SNAPSHOT};
+
+// Must match the channel listened to by the server
+const NOTIFY_CHANNEL: &str = "comet_changes";
+
+pub const DIESEL_CONFIG: &str = r#"[migrations_directory]
+dir = "migrations"
+"#;
+
+// The database the migrations are written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+impl Backend {
+    // SQLite with `--sqlite`, or when `DATABASE_URL` is a file and not a `postgres://` URL
+    pub fn new(sqlite: bool) -> Self {
+        let url = env::var("DATABASE_URL").unwrap_or_default();
+
+        let is_postgres = url.is_empty()
+            || url.starts_with("postgres://")
+            || url.starts_with("postgresql://");
+
+        if sqlite || !is_postgres {
+            Backend::Sqlite
+        } else {
+            Backend::Postgres
+        }
+    }
+}
+
+// Generate a migration for the models that changed since the last one, and run it.
+// With `dry_run`, the migration is only printed.
+pub fn migrate(dry_run: bool, backend: Backend) {
+    let tables = read_models("src");
+
+    let previous = fs::read_to_string(SNAPSHOT)
+        .ok()
+        .map(|content| schema::from_snapshot(&content));
+
+    let changed = match &previous {
+        Some(previous) => !schema::same_tables(previous, &tables),
+        None => true,
+    };
+
+    if changed {
+        let (up, down) = match (&previous, backend) {
+            (Some(previous), Backend::Postgres) => diff(previous, &tables),
+            (None, Backend::Postgres) => initial(&tables),
+            (Some(previous), Backend::Sqlite) => sqlite_diff(previous, &tables),
+            (None, Backend::Sqlite) => sqlite_initial(&tables),
+        };
+
+        if dry_run {
+            println!("-- up.sql\n{}\n-- down.sql\n{}", up, down);
+
+            return;
+        }
+
+        let dir = format!("migrations/{}_comet", timestamp());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(format!("{}/up.sql", dir), up).unwrap();
+        fs::write(format!("{}/down.sql", dir), down).unwrap();
+
+        // See `sqlite_without_foreign_keys`
+        if backend == Backend::Sqlite && previous.is_some() {
+            fs::write(format!("{}/metadata.toml", dir), "run_in_transaction = false\n").unwrap();
+        }
+
+        fs::write(SNAPSHOT, schema::to_snapshot(&tables)).unwrap();
+
+        println!(" -> Generated the migration {}", dir);
+    } else if dry_run {
+        println!("The migrations are up to date");
+
+        return;
+    }
+
+    // Otherwise diesel would print the schema that comet generates from the models
+    if !Path::new("diesel.toml").exists() {
+        fs::write("diesel.toml", DIESEL_CONFIG).unwrap();
+    }
+
+    log_execute("Diesel setup", "diesel", &["setup"]);
+    log_execute("Migrating database", "diesel", &["migration", "run"]);
+}
+
+// Drop the database and run every migration again
+pub fn reset() {
+    log_execute("Reset database", "diesel", &["database", "reset"]);
+}
+
+// Every `#[model]` struct in the sources, in a stable order
+fn read_models(dir: impl AsRef<Path>) -> Vec<Table> {
+    let files = read_sources(dir);
+
+    // The custom types can be declared anywhere, they are needed by the models
+    let mut custom = CustomTypes::default();
+
+    for file in &files {
+        read_custom_types(&file.items, &mut custom);
+    }
+
+    let mut tables = vec![];
+
+    for file in &files {
+        read_items(&file.items, &custom, &mut tables);
+    }
+
+    schema::resolve_foreign_keys(&mut tables).unwrap_or_else(|e| panic!("{}", e));
+
+    tables
+}
+
+fn read_sources(dir: impl AsRef<Path>) -> Vec<syn::File> {
+    let mut paths = fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|entry| Some(entry.ok()?.path())).collect())
+        .unwrap_or_else(|_| vec![]);
+
+    paths.sort();
+
+    let mut files = vec![];
+
+    for path in paths {
+        if path.is_dir() {
+            files.extend(read_sources(&path));
+        } else if path.extension().map(|ext| ext == "rs").unwrap_or(false) {
+            let content = fs::read_to_string(&path).unwrap();
+
+            let file = syn::parse_file(&content)
+                .unwrap_or_else(|e| panic!("Cannot parse {}: {}", path.display(), e));
+
+            files.push(file);
+        }
+    }
+
+    files
+}
+
+fn read_custom_types(items: &[syn::Item], custom: &mut CustomTypes) {
+    for item in items {
+        match item {
+            syn::Item::Struct(item) if has_attr(&item.attrs, "json") => {
+                custom.json.push(item.ident.to_string());
+            }
+            syn::Item::Enum(item) if has_attr(&item.attrs, "db_enum") => {
+                let variants = schema::enum_variants(item)
+                    .unwrap_or_else(|e| panic!("Invalid enum {}: {}", item.ident, e));
+
+                custom.enums.push((item.ident.to_string(), variants));
+            }
+            syn::Item::Mod(module) => {
+                if let Some((_, items)) = &module.content {
+                    read_custom_types(items, custom);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn read_items(items: &[syn::Item], custom: &CustomTypes, tables: &mut Vec<Table>) {
+    for item in items {
+        match item {
+            syn::Item::Struct(item) => {
+                let attr = item
+                    .attrs
+                    .iter()
+                    .find(|attr| last_segment(&attr.path) == "model");
+
+                if let Some(attr) = attr {
+                    let args = schema::model_args(attr)
+                        .unwrap_or_else(|e| panic!("Invalid model {}: {}", item.ident, e));
+
+                    tables.push(schema::struct_to_table(item, &args, custom));
+                }
+            }
+            syn::Item::Mod(module) => {
+                if let Some((_, items)) = &module.content {
+                    read_items(items, custom, tables);
+                }
+            }
+            syn::Item::Macro(item) if last_segment(&item.mac.path) == "auth" => {
+                let file = syn::parse_file(schema::AUTH_MODELS).unwrap();
+
+                read_items(&file.items, custom, tables);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn has_attr(attrs: &[syn::Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| last_segment(&attr.path) == name)
+}
+
+fn last_segment(path: &syn::Path) -> String {
+    path.segments
+        .iter()
+        .last()
+        .map(|segment| segment.ident.to_string())
+        .unwrap_or_default()
+}
+
+// The first migration, creating everything
+fn initial(tables: &[Table]) -> (String, String) {
+    // Notifies the server with the name of the changed table, for the watched queries
+    let mut up = format!(
+        "CREATE FUNCTION comet_notify() RETURNS trigger AS $$
+    BEGIN
+        PERFORM pg_notify('{}', TG_TABLE_NAME);
+        RETURN NULL;
+    END;
+    $$ LANGUAGE plpgsql;
+",
+        NOTIFY_CHANNEL
+    );
+    let mut down = String::new();
+
+    for table in by_dependencies(tables) {
+        up += &create_table(table);
+        down = drop_table(table) + &down;
+    }
+
+    down += "DROP FUNCTION comet_notify();\n";
+
+    (up, down)
+}
+
+// The statements going from the previous schema to the current one, and back.
+// The new tables are created first and the removed ones dropped last, for the foreign keys
+fn diff(previous: &[Table], current: &[Table]) -> (String, String) {
+    let mut up = String::new();
+    let mut down = String::new();
+
+    let is_new = |table: &&Table| !previous.iter().any(|old| old.name == table.name);
+    let is_removed = |old: &&Table| !current.iter().any(|table| table.name == old.name);
+
+    for table in by_dependencies(current).into_iter().filter(is_new) {
+        up += &create_table(table);
+        down = drop_table(table) + &down;
+    }
+
+    for table in current {
+        if let Some(old) = previous.iter().find(|old| old.name == table.name) {
+            let (table_up, table_down) = diff_columns(old, table);
+
+            up += &table_up;
+            down = table_down + &down;
+        }
+    }
+
+    for old in by_dependencies(previous).into_iter().rev().filter(is_removed) {
+        up += &drop_table(old);
+        down = create_table(old) + &down;
+    }
+
+    (up, down)
+}
+
+// The referenced tables come before the ones referencing them
+fn by_dependencies(tables: &[Table]) -> Vec<&Table> {
+    let mut sorted: Vec<&Table> = vec![];
+
+    while sorted.len() < tables.len() {
+        let ready = tables
+            .iter()
+            .filter(|table| !sorted.iter().any(|s| s.name == table.name))
+            .find(|table| {
+                table.columns.iter().all(|column| match &column.references {
+                    Some(references) if *references != table.name => {
+                        sorted.iter().any(|s| s.name == *references)
+                            || !tables.iter().any(|t| t.name == *references)
+                    }
+                    _ => true,
+                })
+            });
+
+        match ready {
+            Some(table) => sorted.push(table),
+            // A cycle, the remaining tables keep their order
+            None => {
+                let remaining = tables
+                    .iter()
+                    .filter(|table| !sorted.iter().any(|s| s.name == table.name))
+                    .collect::<Vec<_>>();
+
+                sorted.extend(remaining);
+            }
+        }
+    }
+
+    sorted
+}
+
+fn diff_columns(old: &Table, new: &Table) -> (String, String) {
+    let table = &new.name;
+
+    // The old key goes away before the columns change, the new one comes after
+    let (drop_key, add_key) = change_primary_key(old, new);
+    let (drop_key_back, add_key_back) = change_primary_key(new, old);
+
+    let mut up = drop_key;
+    let mut down = drop_key_back;
+
+    let (added, dropped) = changed_columns(old, new);
+    let renamed = renamed_columns(old, new);
+
+    for (from, to) in &renamed {
+        up += &rename_column(table, from, to);
+    }
+
+    for column in added {
+        if renamed.iter().any(|(_, to)| to.name == column.name) {
+            continue;
+        }
+
+        up += &add_column(table, column);
+        down += &format!("ALTER TABLE {} DROP COLUMN {};\n", table, column.name);
+    }
+
+    for column in dropped {
+        if renamed.iter().any(|(from, _)| from.name == column.name) {
+            continue;
+        }
+
+        up += &format!("ALTER TABLE {} DROP COLUMN {};\n", table, column.name);
+        down += &add_column(table, column);
+    }
+
+    for column in &new.columns {
+        let previous = old
+            .columns
+            .iter()
+            .find(|c| c.name == column.name)
+            .or_else(|| {
+                renamed
+                    .iter()
+                    .find(|(_, to)| to.name == column.name)
+                    .map(|(from, _)| *from)
+            });
+
+        if let Some(previous) = previous {
+            // A renamed column already has its new name, and its constraints too
+            let previous = Column {
+                name: column.name.clone(),
+                ..previous.clone()
+            };
+
+            if previous == *column {
+                continue;
+            }
+
+            up += &alter_column(table, &previous, column);
+            down += &alter_column(table, column, &previous);
+        }
+    }
+
+    // Once changed back, under their new name
+    for (from, to) in &renamed {
+        down += &rename_column(table, to, from);
+    }
+
+    up += &add_key;
+    down += &add_key_back;
+
+    (up, down)
+}
+
+// The columns added to a table, and the ones dropped from it
+fn changed_columns<'a>(old: &'a Table, new: &'a Table) -> (Vec<&'a Column>, Vec<&'a Column>) {
+    let added = new
+        .columns
+        .iter()
+        .filter(|column| !old.columns.iter().any(|c| c.name == column.name))
+        .collect();
+    let dropped = old
+        .columns
+        .iter()
+        .filter(|column| !new.columns.iter().any(|c| c.name == column.name))
+        .collect();
+
+    (added, dropped)
+}
+
+// The columns renamed with `#[renamed_from("name")]`, from their old column to their new one,
+// so their data is kept. Any other column with a new name is dropped, and the new one added
+fn renamed_columns<'a>(old: &'a Table, new: &'a Table) -> Vec<(&'a Column, &'a Column)> {
+    let (added, dropped) = changed_columns(old, new);
+
+    added
+        .into_iter()
+        .filter_map(|to| {
+            let from = dropped
+                .iter()
+                .find(|from| Some(&from.name) == to.renamed_from.as_ref())?;
+
+            Some((*from, to))
+        })
+        .collect()
+}
+
+// A SERIAL id can grow to a BIGSERIAL one, any other change replaces the key.
+// A new id numbers the existing rows, or gives them a random UUID
+fn change_primary_key(old: &Table, new: &Table) -> (String, String) {
+    let table = &new.name;
+
+    if old.id == new.id && old.primary_key() == new.primary_key() {
+        return (String::new(), String::new());
+    }
+
+    let integer = |id: &str| match id {
+        "SERIAL" => Some("INTEGER"),
+        "BIGSERIAL" => Some("BIGINT"),
+        _ => None,
+    };
+
+    if let (Some(_), Some(to)) = (
+        old.id.as_deref().and_then(integer),
+        new.id.as_deref().and_then(integer),
+    ) {
+        return (
+            String::new(),
+            format!(
+                "ALTER TABLE {table} ALTER COLUMN id TYPE {ty};
+ALTER SEQUENCE {table}_id_seq AS {ty};
+",
+                table = table,
+                ty = to,
+            ),
+        );
+    }
+
+    let drop_key = match &old.id {
+        Some(_) => format!("ALTER TABLE {} DROP COLUMN id;\n", table),
+        None => format!("ALTER TABLE {table} DROP CONSTRAINT {table}_pkey;\n", table = table),
+    };
+
+    let add_key = match new.id.as_deref() {
+        Some("UUID") => format!(
+            "ALTER TABLE {table} ADD COLUMN id UUID PRIMARY KEY DEFAULT gen_random_uuid();
+ALTER TABLE {table} ALTER COLUMN id DROP DEFAULT;
+",
+            table = table,
+        ),
+        Some(id) => format!("ALTER TABLE {} ADD COLUMN id {} PRIMARY KEY;\n", table, id),
+        None => format!(
+            "ALTER TABLE {} ADD PRIMARY KEY ({});\n",
+            table,
+            new.primary_key().join(", ")
+        ),
+    };
+
+    (drop_key, add_key)
+}
+
+fn create_table(table: &Table) -> String {
+    let mut columns = table
+        .columns
+        .iter()
+        .map(|column| format!("    {} {}", column.name, column_type(&table.name, column)))
+        .collect::<Vec<_>>();
+
+    match &table.id {
+        Some(id) => columns.insert(0, format!("    id {} PRIMARY KEY", id)),
+        None => columns.push(format!("    PRIMARY KEY ({})", table.primary_key().join(", "))),
+    }
+
+    let columns = columns.join(",\n");
+
+    let indexes = table
+        .columns
+        .iter()
+        .filter(|column| column.index)
+        .map(|column| create_index(&table.name, column))
+        .collect::<String>();
+
+    format!(
+        "CREATE TABLE {name} (
+{columns}
+);
+{indexes}CREATE TRIGGER {name}_notify
+    AFTER INSERT OR UPDATE OR DELETE ON {name}
+    FOR EACH STATEMENT EXECUTE PROCEDURE comet_notify();
+",
+        name = table.name,
+        columns = columns,
+        indexes = indexes,
+    )
+}
+
+fn drop_table(table: &Table) -> String {
+    format!("DROP TABLE {};\n", table.name)
+}
+
+fn column_type(table: &str, column: &Column) -> String {
+    let mut ty = column.ty.clone();
+
+    if !column.nullable {
+        ty += " NOT NULL";
+    }
+
+    if let Some(default) = &column.default {
+        ty += &format!(" DEFAULT {}", default);
+    }
+
+    if column.unique {
+        ty += &format!(" CONSTRAINT {} UNIQUE", unique_name(table, column));
+    }
+
+    if !column.variants.is_empty() {
+        ty += &format!(" {}", check(table, column));
+    }
+
+    if column.references.is_some() {
+        ty += &format!(" {}", foreign_key(table, column));
+    }
+
+    ty
+}
+
+// The rows of a deleted model are deleted with it.
+// Without a column, the primary key of the other table is referenced
+fn foreign_key(table: &str, column: &Column) -> String {
+    format!(
+        "CONSTRAINT {}_{}_fkey REFERENCES {} ON DELETE CASCADE",
+        table,
+        column.name,
+        column.references.as_ref().unwrap()
+    )
+}
+
+// Restrict a `#[db_enum]` column to the names of its variants
+fn check(table: &str, column: &Column) -> String {
+    let variants = column
+        .variants
+        .iter()
+        .map(|variant| format!("'{}'", variant))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let condition = if column.ty.ends_with("[]") {
+        format!("{} <@ ARRAY[{}]::TEXT[]", column.name, variants)
+    } else {
+        format!("{} IN ({})", column.name, variants)
+    };
+
+    format!(
+        "CONSTRAINT {} CHECK ({})",
+        constraint_name(table, column),
+        condition
+    )
+}
+
+fn constraint_name(table: &str, column: &Column) -> String {
+    format!("{}_{}_check", table, column.name)
+}
+
+fn unique_name(table: &str, column: &Column) -> String {
+    format!("{}_{}_key", table, column.name)
+}
+
+fn index_name(table: &str, column: &Column) -> String {
+    format!("{}_{}_idx", table, column.name)
+}
+
+fn create_index(table: &str, column: &Column) -> String {
+    format!(
+        "CREATE INDEX {} ON {} ({});\n",
+        index_name(table, column),
+        table,
+        column.name
+    )
+}
+
+fn rename_column(table: &str, from: &Column, to: &Column) -> String {
+    let mut sql = format!(
+        "ALTER TABLE {} RENAME COLUMN {} TO {};\n",
+        table, from.name, to.name
+    );
+
+    if !from.variants.is_empty() {
+        sql += &format!(
+            "ALTER TABLE {} RENAME CONSTRAINT {} TO {};\n",
+            table,
+            constraint_name(table, from),
+            constraint_name(table, to)
+        );
+    }
+
+    if from.references.is_some() {
+        sql += &format!(
+            "ALTER TABLE {table} RENAME CONSTRAINT {table}_{}_fkey TO {table}_{}_fkey;\n",
+            from.name,
+            to.name,
+            table = table,
+        );
+    }
+
+    if from.unique {
+        sql += &format!(
+            "ALTER TABLE {} RENAME CONSTRAINT {} TO {};\n",
+            table,
+            unique_name(table, from),
+            unique_name(table, to)
+        );
+    }
+
+    if from.index {
+        sql += &format!(
+            "ALTER INDEX {} RENAME TO {};\n",
+            index_name(table, from),
+            index_name(table, to)
+        );
+    }
+
+    sql
+}
+
+// The existing rows get the `#[default]` or the zero value of the type, or NULL.
+// A required foreign key can only be added to an empty table
+fn add_column(table: &str, column: &Column) -> String {
+    let mut sql = if column.references.is_some() && !column.nullable {
+        let nullable = Column {
+            nullable: true,
+            ..column.clone()
+        };
+
+        format!(
+            "ALTER TABLE {table} ADD COLUMN {name} {ty};
+ALTER TABLE {table} ALTER COLUMN {name} SET NOT NULL;
+",
+            table = table,
+            name = column.name,
+            ty = column_type(table, &nullable),
+        )
+    } else if column.nullable || column.default.is_some() {
+        format!(
+            "ALTER TABLE {} ADD COLUMN {} {};\n",
+            table,
+            column.name,
+            column_type(table, column)
+        )
+    } else {
+        let with_default = Column {
+            default: Some(default_value(column)),
+            ..column.clone()
+        };
+
+        format!(
+            "ALTER TABLE {table} ADD COLUMN {name} {ty};
+ALTER TABLE {table} ALTER COLUMN {name} DROP DEFAULT;
+",
+            table = table,
+            name = column.name,
+            ty = column_type(table, &with_default),
+        )
+    };
+
+    if column.index {
+        sql += &create_index(table, column);
+    }
+
+    sql
+}
+
+// Change the type, the nullability, the default, the variants or the indexes of a column
+fn alter_column(table: &str, from: &Column, to: &Column) -> String {
+    let mut sql = String::new();
+
+    if !from.variants.is_empty() && from.variants != to.variants {
+        sql += &format!(
+            "ALTER TABLE {} DROP CONSTRAINT {};\n",
+            table,
+            constraint_name(table, from)
+        );
+    }
+
+    if from.references.is_some() && from.references != to.references {
+        sql += &format!(
+            "ALTER TABLE {table} DROP CONSTRAINT {table}_{}_fkey;\n",
+            from.name,
+            table = table,
+        );
+    }
+
+    if from.ty != to.ty {
+        sql += &format!(
+            "ALTER TABLE {table} ALTER COLUMN {name} TYPE {ty} USING {name}::{ty};\n",
+            table = table,
+            name = to.name,
+            ty = to.ty,
+        );
+    }
+
+    if from.default != to.default {
+        sql += &match &to.default {
+            Some(default) => format!(
+                "ALTER TABLE {} ALTER COLUMN {} SET DEFAULT {};\n",
+                table, to.name, default
+            ),
+            None => format!(
+                "ALTER TABLE {} ALTER COLUMN {} DROP DEFAULT;\n",
+                table, to.name
+            ),
+        };
+    }
+
+    if from.nullable != to.nullable {
+        sql += &alter_nullable(table, to);
+    }
+
+    if from.unique != to.unique {
+        sql += &if to.unique {
+            format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} UNIQUE ({});\n",
+                table,
+                unique_name(table, to),
+                to.name
+            )
+        } else {
+            format!(
+                "ALTER TABLE {} DROP CONSTRAINT {};\n",
+                table,
+                unique_name(table, from)
+            )
+        };
+    }
+
+    if from.index != to.index {
+        sql += &if to.index {
+            create_index(table, to)
+        } else {
+            format!("DROP INDEX {};\n", index_name(table, from))
+        };
+    }
+
+    if !to.variants.is_empty() && from.variants != to.variants {
+        sql += &format!("ALTER TABLE {} ADD {};\n", table, check(table, to));
+    }
+
+    if let Some(references) = to.references.as_ref().filter(|_| from.references != to.references) {
+        sql += &format!(
+            "ALTER TABLE {table} ADD CONSTRAINT {table}_{name}_fkey FOREIGN KEY ({name}) REFERENCES {references} ON DELETE CASCADE;\n",
+            table = table,
+            name = to.name,
+            references = references,
+        );
+    }
+
+    sql
+}
+
+// The NULL values get the zero value of the type when the column becomes required
+fn alter_nullable(table: &str, column: &Column) -> String {
+    if column.nullable {
+        return format!(
+            "ALTER TABLE {} ALTER COLUMN {} DROP NOT NULL;\n",
+            table, column.name
+        );
+    }
+
+    format!(
+        "UPDATE {table} SET {name} = {default} WHERE {name} IS NULL;
+ALTER TABLE {table} ALTER COLUMN {name} SET NOT NULL;
+",
+        table = table,
+        name = column.name,
+        default = default_value(column),
+    )
+}
+
+fn default_value(column: &Column) -> String {
+    if let Some(default) = &column.default {
+        return default.clone();
+    }
+
+    if column.ty.ends_with("[]") {
+        return "'{}'".to_string();
+    }
+
+    if let Some(variant) = column.variants.first() {
+        return format!("'{}'", variant);
+    }
+
+    match column.ty.as_str() {
+        "TEXT" | "BYTEA" => "''",
+        ty if ty.starts_with("VARCHAR(") => "''",
+        "BOOLEAN" => "false",
+        "TIMESTAMP" | "TIMESTAMPTZ" => "now()",
+        "DATE" => "CURRENT_DATE",
+        "UUID" => "'00000000-0000-0000-0000-000000000000'",
+        "JSONB" => "'null'",
+        _ => "0",
+    }
+    .to_string()
+}
+
+// SQLite has no trigger notifying the server, the model methods do it themselves
+fn sqlite_initial(tables: &[Table]) -> (String, String) {
+    let mut up = String::new();
+    let mut down = String::new();
+
+    for table in by_dependencies(tables) {
+        up += &sqlite_create_table(&table.name, table);
+        down = drop_table(table) + &down;
+    }
+
+    (up, down)
+}
+
+// SQLite can't change a column or a constraint, a changed table is copied into a new one instead
+fn sqlite_diff(previous: &[Table], current: &[Table]) -> (String, String) {
+    let mut up = String::new();
+    let mut down = String::new();
+
+    let is_new = |table: &&Table| !previous.iter().any(|old| old.name == table.name);
+    let is_removed = |old: &&Table| !current.iter().any(|table| table.name == old.name);
+
+    for table in by_dependencies(current).into_iter().filter(is_new) {
+        up += &sqlite_create_table(&table.name, table);
+        down = drop_table(table) + &down;
+    }
+
+    for table in current {
+        if let Some(old) = previous.iter().find(|old| old.name == table.name && *old != table) {
+            let renamed = renamed_columns(old, table);
+            let renamed_back = renamed.iter().map(|(from, to)| (*to, *from)).collect::<Vec<_>>();
+
+            up += &sqlite_rebuild_table(old, table, &renamed);
+            down = sqlite_rebuild_table(table, old, &renamed_back) + &down;
+        }
+    }
+
+    for old in by_dependencies(previous).into_iter().rev().filter(is_removed) {
+        up += &drop_table(old);
+        down = sqlite_create_table(&old.name, old) + &down;
+    }
+
+    (sqlite_without_foreign_keys(&up), sqlite_without_foreign_keys(&down))
+}
+
+// With its foreign keys on, SQLite deletes the rows referencing a table it drops. They are
+// turned off while the tables are rebuilt, which can't be done in a transaction: diesel is told
+// not to start one and the migration runs its own. `PRAGMA foreign_key_check` only lists the rows
+// left without their parent, inserting them in a checked table fails the migration instead
+fn sqlite_without_foreign_keys(sql: &str) -> String {
+    format!(
+        "PRAGMA foreign_keys = OFF;
+BEGIN;
+{}CREATE TEMP TABLE comet_foreign_key_check (violation INTEGER CHECK (violation IS NULL));
+INSERT INTO comet_foreign_key_check SELECT 1 FROM pragma_foreign_key_check;
+DROP TABLE comet_foreign_key_check;
+COMMIT;
+PRAGMA foreign_keys = ON;
+",
+        sql
+    )
+}
+
+// The table created under `name`, with its indexes when it has its own name
+fn sqlite_create_table(name: &str, table: &Table) -> String {
+    let sqlite_column = |column: &Column| Column {
+        ty: schema::sqlite_type(&column.ty)
+            .unwrap_or_else(|| panic!("{} is not supported by SQLite", column.ty))
+            .to_string(),
+        ..column.clone()
+    };
+
+    let mut columns = table
+        .columns
+        .iter()
+        .map(|column| {
+            format!(
+                "    {} {}",
+                column.name,
+                column_type(&table.name, &sqlite_column(column))
+            )
+        })
+        .collect::<Vec<_>>();
+
+    match &table.id {
+        Some(_) => columns.insert(0, "    id INTEGER PRIMARY KEY".to_string()),
+        None => columns.push(format!("    PRIMARY KEY ({})", table.primary_key().join(", "))),
+    }
+
+    let indexes = if name == table.name {
+        table
+            .columns
+            .iter()
+            .filter(|column| column.index)
+            .map(|column| create_index(&table.name, column))
+            .collect::<String>()
+    } else {
+        String::new()
+    };
+
+    format!(
+        "CREATE TABLE {} (\n{}\n);\n{}",
+        name,
+        columns.join(",\n"),
+        indexes
+    )
+}
+
+// Copy the rows of `old` into a new table shaped like `new`, that replaces it.
+// The new required columns get their `#[default]` or the zero value of their type,
+// except the foreign keys that can only be added to an empty table.
+// Run with the foreign keys off, dropping `old` keeps the rows referencing it
+fn sqlite_rebuild_table(old: &Table, new: &Table, renamed: &[(&Column, &Column)]) -> String {
+    let copy = format!("comet_new_{}", new.name);
+
+    let mut columns = vec![];
+    let mut values = vec![];
+
+    if old.id.is_some() && new.id.is_some() {
+        columns.push("id".to_string());
+        values.push("id".to_string());
+    }
+
+    for column in &new.columns {
+        let from = old
+            .columns
+            .iter()
+            .find(|c| c.name == column.name)
+            .or_else(|| {
+                renamed
+                    .iter()
+                    .find(|(_, to)| to.name == column.name)
+                    .map(|(from, _)| *from)
+            });
+
+        let value = match from {
+            Some(from) if from.nullable && !column.nullable => {
+                format!("COALESCE({}, {})", from.name, sqlite_default_value(column))
+            }
+            Some(from) => from.name.clone(),
+            None if column.nullable || column.default.is_some() || column.references.is_some() => {
+                continue
+            }
+            None => sqlite_default_value(column),
+        };
+
+        columns.push(column.name.clone());
+        values.push(value);
+    }
+
+    let indexes = new
+        .columns
+        .iter()
+        .filter(|column| column.index)
+        .map(|column| create_index(&new.name, column))
+        .collect::<String>();
+
+    format!(
+        "{create}INSERT INTO {copy} ({columns}) SELECT {values} FROM {name};
+DROP TABLE {name};
+ALTER TABLE {copy} RENAME TO {name};
+{indexes}",
+        create = sqlite_create_table(&copy, new),
+        copy = copy,
+        columns = columns.join(", "),
+        values = values.join(", "),
+        name = new.name,
+        indexes = indexes,
+    )
+}
+
+fn sqlite_default_value(column: &Column) -> String {
+    match default_value(column).as_str() {
+        "now()" => "CURRENT_TIMESTAMP".to_string(),
+        value => value.to_string(),
+    }
+}
+
+// The version prefix expected by diesel, `YYYY-MM-DD-HHMMSS`, in UTC
+fn timestamp() -> String {
+    chrono::Utc::now().format("%Y-%m-%d-%H%M%S").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, ty: &str) -> Column {
+        Column {
+            name: name.to_string(),
+            ty: ty.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn todos(columns: Vec<Column>) -> Table {
+        Table {
+            name: "todos".to_string(),
+            id: Some("SERIAL".to_string()),
+            columns,
+            model: "Todo".to_string(),
+        }
+    }
+
+    #[test]
+    fn columns_are_added_dropped_and_altered() {
+        let old = todos(vec![column("title", "TEXT"), column("done", "BOOLEAN")]);
+        let new = todos(vec![
+            Column {
+                nullable: true,
+                ..column("title", "VARCHAR(40)")
+            },
+            column("priority", "INTEGER"),
+        ]);
+
+        let (up, down) = diff_columns(&old, &new);
+
+        assert_eq!(
+            up,
+            "ALTER TABLE todos ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE todos ALTER COLUMN priority DROP DEFAULT;
+ALTER TABLE todos DROP COLUMN done;
+ALTER TABLE todos ALTER COLUMN title TYPE VARCHAR(40) USING title::VARCHAR(40);
+ALTER TABLE todos ALTER COLUMN title DROP NOT NULL;
+"
+        );
+        assert_eq!(
+            down,
+            "ALTER TABLE todos DROP COLUMN priority;
+ALTER TABLE todos ADD COLUMN done BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE todos ALTER COLUMN done DROP DEFAULT;
+ALTER TABLE todos ALTER COLUMN title TYPE TEXT USING title::TEXT;
+UPDATE todos SET title = '' WHERE title IS NULL;
+ALTER TABLE todos ALTER COLUMN title SET NOT NULL;
+"
+        );
+    }
+
+    #[test]
+    fn only_the_declared_renames_keep_their_data() {
+        let old = todos(vec![column("title", "TEXT"), column("done", "BOOLEAN")]);
+        let new = todos(vec![
+            Column {
+                renamed_from: Some("title".to_string()),
+                ..column("name", "TEXT")
+            },
+            column("completed", "BOOLEAN"),
+        ]);
+
+        let renamed = renamed_columns(&old, &new);
+
+        assert_eq!(renamed.len(), 1);
+        assert_eq!((renamed[0].0.name.as_str(), renamed[0].1.name.as_str()), ("title", "name"));
+
+        let (up, down) = diff_columns(&old, &new);
+
+        assert!(up.starts_with("ALTER TABLE todos RENAME COLUMN title TO name;\n"));
+        assert!(up.contains("ALTER TABLE todos DROP COLUMN done;\n"));
+        assert!(!up.contains("DROP COLUMN title"));
+        assert!(down.ends_with("ALTER TABLE todos RENAME COLUMN name TO title;\n"));
+    }
+
+    #[test]
+    fn sqlite_rebuilds_copy_the_renamed_columns() {
+        let old = todos(vec![column("title", "TEXT"), column("done", "BOOLEAN")]);
+        let new = todos(vec![
+            Column {
+                renamed_from: Some("title".to_string()),
+                ..column("name", "TEXT")
+            },
+            column("completed", "BOOLEAN"),
+        ]);
+
+        let (up, _) = sqlite_diff(&[old], &[new]);
+
+        assert!(up.starts_with("PRAGMA foreign_keys = OFF;\nBEGIN;\n"));
+        assert!(up.contains(
+            "INSERT INTO comet_new_todos (id, name, completed) SELECT id, title, false FROM todos;\n"
+        ));
+        assert!(up.ends_with("COMMIT;\nPRAGMA foreign_keys = ON;\n"));
+    }
+}
@@ -85,5 +85,7 @@ comet-web = "{{version}}"
         .replace("{{name}}", name),
     );
 
+    create_file("diesel.toml", crate::migrate::DIESEL_CONFIG);
+
     create_file("README.md", "");
 }
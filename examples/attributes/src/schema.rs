@@ -1,2 +0,0 @@
-// @generated automatically by Diesel CLI.
-
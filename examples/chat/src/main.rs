@@ -0,0 +1,110 @@
+use comet::prelude::*;
+
+#[model]
+#[derive(Default, Debug)]
+pub struct Post {
+    pub author: String,
+    pub content: String,
+}
+
+#[rpc]
+impl Post {
+    // Subscribe the caller to the new messages and return the history
+    pub async fn join() -> Vec<Post> {
+        join_room("chat").await;
+
+        Post::list().await.unwrap()
+    }
+
+    pub async fn post(&self) {
+        let message = self.create().await.unwrap();
+
+        broadcast("chat", &message).await;
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    messages: Vec<Post>,
+}
+
+component! {
+    History,
+    div {
+        for message in (&self.messages) {
+            div {
+                { message.author }
+                { ": " }
+                { message.content }
+            }
+        }
+    }
+}
+
+pub struct App {
+    author: String,
+    content: String,
+    history: Shared<History>,
+}
+
+impl App {
+    pub async fn new() -> Self {
+        let history: Shared<History> = History {
+            messages: Post::join().await,
+        }
+        .into();
+
+        // Every message posted by any client is pushed to the "chat" room
+        #[cfg(target_arch = "wasm32")]
+        {
+            let history = history.clone();
+
+            on_broadcast("chat", move |message: Post| {
+                let history = history.clone();
+
+                async move {
+                    history.write().await.messages.push(message);
+                }
+            });
+        }
+
+        Self {
+            author: "".into(),
+            content: "".into(),
+            history,
+        }
+    }
+
+    pub async fn send(&mut self) {
+        Post {
+            id: None,
+            author: self.author.clone(),
+            content: self.content.clone(),
+        }
+        .post()
+        .await;
+
+        self.content = "".into();
+    }
+}
+
+component! {
+    App,
+    div {
+        @{self.history}
+        input ={ self.author } {}
+        input ={ self.content } {}
+        button @click: { self.send().await } {
+            { "Send" }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn server_config() -> ServerConfig {
+    ServerConfig::default().on_disconnect(|session_id| async move {
+        println!(" -> Client {} left the chat", session_id);
+    })
+}
+
+comet::run!(App::new().await, server_config());
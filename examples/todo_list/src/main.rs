@@ -51,7 +51,7 @@ impl App {
     pub async fn new_todo(&mut self) {
         self.list.push(
             Todo {
-                id: -1,
+                id: None,
                 title: self.title.clone(),
                 completed: false,
             }
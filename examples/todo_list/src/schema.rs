@@ -1,9 +0,0 @@
-// @generated automatically by Diesel CLI.
-
-crate::diesel::table! {
-    todos (id) {
-        id -> Int4,
-        title -> Text,
-        completed -> Bool,
-    }
-}
@@ -0,0 +1,81 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse_macro_input;
+
+use crate::{generate_migrations::CUSTOM_TYPES, schema};
+
+// Store the enum as TEXT, restricted to its variant names, when used in a #[model]
+pub fn perform(input: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(input as syn::ItemEnum);
+
+    let variants = match schema::enum_variants(&item) {
+        Ok(variants) => variants,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    let name = item.ident.clone();
+
+    {
+        let mut custom = CUSTOM_TYPES.write().unwrap();
+
+        let enum_name = name.to_string();
+
+        custom.enums.retain(|(registered, _)| *registered != enum_name);
+        custom.enums.push((enum_name, variants.clone()));
+    }
+
+    let idents = item
+        .variants
+        .iter()
+        .map(|variant| variant.ident.clone())
+        .collect::<Vec<_>>();
+    let idents2 = idents.clone();
+    let names = variants.clone();
+    let names2 = variants;
+
+    // tricks to repeat the enum name along its variants
+    let name_vec = vec![name.clone(); idents.len()];
+    let name_vec2 = name_vec.clone();
+
+    let tt = quote! {
+        #[cfg_attr(not(target_arch = "wasm32"), derive(AsExpression, FromSqlRow))]
+        #[cfg_attr(not(target_arch = "wasm32"), diesel(sql_type = diesel::sql_types::Text))]
+        #item
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl diesel::deserialize::FromSql<diesel::sql_types::Text, comet::prelude::DbBackend> for #name {
+            fn from_sql(
+                bytes: diesel::backend::RawValue<'_, comet::prelude::DbBackend>,
+            ) -> diesel::deserialize::Result<Self> {
+                let value = <String as diesel::deserialize::FromSql<
+                    diesel::sql_types::Text,
+                    comet::prelude::DbBackend,
+                >>::from_sql(bytes)?;
+
+                match value.as_str() {
+                    #(#names => Ok(#name_vec::#idents),)*
+                    other => Err(format!("Unknown variant {} for {}", other, stringify!(#name)).into()),
+                }
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl diesel::serialize::ToSql<diesel::sql_types::Text, comet::prelude::DbBackend> for #name {
+            fn to_sql<'b>(
+                &'b self,
+                out: &mut diesel::serialize::Output<'b, '_, comet::prelude::DbBackend>,
+            ) -> diesel::serialize::Result {
+                let value = match self {
+                    #(#name_vec2::#idents2 => #names2,)*
+                };
+
+                <str as diesel::serialize::ToSql<diesel::sql_types::Text, comet::prelude::DbBackend>>::to_sql(
+                    value,
+                    out,
+                )
+            }
+        }
+    };
+
+    tt.into()
+}
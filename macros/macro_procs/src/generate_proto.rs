@@ -42,7 +42,8 @@ pub fn exprs_to_idents(_mcall: TokenStream) -> Result<proc_macro2::TokenStream>
 
         }
 
-        #[async_trait]
+        #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+        #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
         impl comet::prelude::ProtoTrait for Proto {
             type Response = Proto;
 
@@ -54,6 +55,13 @@ pub fn exprs_to_idents(_mcall: TokenStream) -> Result<proc_macro2::TokenStream>
                     _ => todo!(),
                 }
             }
+
+            fn error(&self) -> Option<String> {
+                match self {
+                    Proto::RPCResponse(rpc_proto) => rpc_proto.error(),
+                    _ => None,
+                }
+            }
         }
     };
 
@@ -0,0 +1,96 @@
+use proc_macro::TokenStream;
+use quote::quote;
+
+use crate::{generate_migrations::MIGRATIONS, schema};
+
+// The diesel `table!` definitions of every model, in a `schema` module
+pub fn perform(_input: TokenStream) -> TokenStream {
+    let mut tables = MIGRATIONS.read().unwrap().clone();
+
+    if let Err(e) = schema::resolve_foreign_keys(&mut tables) {
+        return quote! { compile_error!(#e); }.into();
+    }
+
+    let definitions = tables.iter().map(|table| {
+        let name: syn::Ident = syn::parse_str(&table.name).unwrap();
+
+        let columns = table.columns.iter().map(|column| {
+            let column_name: syn::Ident = syn::parse_str(&column.name).unwrap();
+            let column_type: syn::Type =
+                syn::parse_str(&schema::diesel_type(&column.ty)).unwrap();
+
+            if column.nullable {
+                quote! { #column_name -> Nullable<#column_type>, }
+            } else {
+                quote! { #column_name -> #column_type, }
+            }
+        });
+
+        let primary_key = table
+            .primary_key()
+            .iter()
+            .map(|column| syn::parse_str::<syn::Ident>(column).unwrap())
+            .collect::<Vec<_>>();
+
+        let id = table.id.as_ref().map(|id| {
+            let id_type: syn::Type = syn::parse_str(&schema::diesel_type(id)).unwrap();
+
+            quote! { id -> #id_type, }
+        });
+
+        quote! {
+            crate::diesel::table! {
+                #name (#(#primary_key),*) {
+                    #id
+                    #(#columns)*
+                }
+            }
+        }
+    });
+
+    // Only the foreign keys to a known table, the other one may be missing from the build
+    let joins = tables.iter().flat_map(|table| {
+        let child: syn::Ident = syn::parse_str(&table.name).unwrap();
+
+        table
+            .columns
+            .iter()
+            .filter(|column| {
+                column
+                    .references
+                    .as_ref()
+                    .map(|references| tables.iter().any(|t| t.name == *references))
+                    .unwrap_or(false)
+            })
+            .map(move |column| {
+                let parent: syn::Ident =
+                    syn::parse_str(column.references.as_ref().unwrap()).unwrap();
+                let foreign_key: syn::Ident = syn::parse_str(&column.name).unwrap();
+
+                quote! { crate::diesel::joinable!(#child -> #parent (#foreign_key)); }
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let joinable = if tables.len() > 1 {
+        let names = tables
+            .iter()
+            .map(|table| syn::parse_str::<syn::Ident>(&table.name).unwrap());
+
+        quote! { crate::diesel::allow_tables_to_appear_in_same_query!(#(#names),*); }
+    } else {
+        quote! {}
+    };
+
+    let tt = quote! {
+        pub mod schema {
+            #(#definitions)*
+
+            #(#joins)*
+
+            #joinable
+        }
+    };
+
+    tt.into()
+}
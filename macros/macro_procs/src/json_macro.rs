@@ -0,0 +1,55 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::parse_macro_input;
+
+use crate::generate_migrations::CUSTOM_TYPES;
+
+// Store the struct as JSONB when used in a #[model]
+pub fn perform(input: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(input as syn::ItemStruct);
+
+    let name = item.ident.clone();
+
+    {
+        let mut custom = CUSTOM_TYPES.write().unwrap();
+
+        if !custom.json.contains(&name.to_string()) {
+            custom.json.push(name.to_string());
+        }
+    }
+
+    let tt = quote! {
+        #[cfg_attr(not(target_arch = "wasm32"), derive(AsExpression, FromSqlRow))]
+        #[cfg_attr(not(target_arch = "wasm32"), diesel(sql_type = diesel::sql_types::Jsonb))]
+        #item
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl diesel::deserialize::FromSql<diesel::sql_types::Jsonb, diesel::pg::Pg> for #name {
+            fn from_sql(bytes: diesel::pg::PgValue<'_>) -> diesel::deserialize::Result<Self> {
+                let value = <comet::prelude::serde_json::Value as diesel::deserialize::FromSql<
+                    diesel::sql_types::Jsonb,
+                    diesel::pg::Pg,
+                >>::from_sql(bytes)?;
+
+                Ok(comet::prelude::serde_json::from_value(value)?)
+            }
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl diesel::serialize::ToSql<diesel::sql_types::Jsonb, diesel::pg::Pg> for #name {
+            fn to_sql<'b>(
+                &'b self,
+                out: &mut diesel::serialize::Output<'b, '_, diesel::pg::Pg>,
+            ) -> diesel::serialize::Result {
+                let value = comet::prelude::serde_json::to_value(self)?;
+
+                <comet::prelude::serde_json::Value as diesel::serialize::ToSql<
+                    diesel::sql_types::Jsonb,
+                    diesel::pg::Pg,
+                >>::to_sql(&value, &mut out.reborrow())
+            }
+        }
+    };
+
+    tt.into()
+}
@@ -4,15 +4,20 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 
+mod db_enum_macro;
 mod db_macro;
 mod generate_hash;
 mod generate_migrations;
 mod generate_msg;
 mod generate_proto;
+mod generate_schema;
 mod generate_update;
+mod json_macro;
 mod model_macro;
 mod rpc_macro;
+mod schema;
 mod sql_macro;
+mod validate;
 
 mod utils;
 
@@ -36,6 +41,16 @@ pub fn db(_attr: TokenStream, input: TokenStream) -> TokenStream {
     db_macro::perform(input)
 }
 
+#[proc_macro_attribute]
+pub fn json(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    json_macro::perform(input)
+}
+
+#[proc_macro_attribute]
+pub fn db_enum(_attr: TokenStream, input: TokenStream) -> TokenStream {
+    db_enum_macro::perform(input)
+}
+
 #[proc_macro]
 pub fn generate_proto(input: TokenStream) -> TokenStream {
     generate_proto::perform(input)
@@ -43,9 +58,7 @@ pub fn generate_proto(input: TokenStream) -> TokenStream {
 
 #[proc_macro_attribute]
 pub fn model(attr: TokenStream, input: TokenStream) -> TokenStream {
-    let table_name = attr.to_string();
-
-    model_macro::perform(table_name, input)
+    model_macro::perform(attr, input)
 }
 
 #[proc_macro]
@@ -53,14 +66,30 @@ pub fn generate_migrations(input: TokenStream) -> TokenStream {
     generate_migrations::perform(input)
 }
 
+#[proc_macro]
+pub fn generate_schema(input: TokenStream) -> TokenStream {
+    generate_schema::perform(input)
+}
+
 #[proc_macro_attribute]
-pub fn sql(_attr: TokenStream, input: TokenStream) -> TokenStream {
-    sql_macro::perform(input)
+pub fn sql(attr: TokenStream, input: TokenStream) -> TokenStream {
+    sql_macro::perform(attr, input)
 }
 
 #[proc_macro_attribute]
-pub fn rpc(_attr: TokenStream, input: TokenStream) -> TokenStream {
-    rpc_macro::perform(input)
+pub fn rpc(attr: TokenStream, input: TokenStream) -> TokenStream {
+    rpc_macro::perform(attr, input)
+}
+
+#[proc_macro]
+pub fn generate_model_enum(input: TokenStream) -> TokenStream {
+    model_macro::generate_model_enum(input)
+}
+
+// The `User` and `Session` models of `comet::auth!`
+#[proc_macro]
+pub fn auth_models(_input: TokenStream) -> TokenStream {
+    schema::AUTH_MODELS.parse().unwrap()
 }
 
 #[proc_macro]
@@ -1,25 +1,232 @@
 use proc_macro::TokenStream;
 
+use lazy_static::lazy_static;
 use quote::quote;
+use std::sync::{Arc, RwLock};
 use syn::{parse::Result, parse_macro_input, Fields, ItemStruct};
 
-pub fn perform(table_name: String, input: TokenStream) -> TokenStream {
+use crate::generate_migrations::CUSTOM_TYPES;
+use crate::schema::{self, FieldAttrs, ModelArgs, Relation, RelationKind, ENDPOINTS};
+use crate::validate;
+
+// The client cache ids of the queries that are not endpoints
+const FETCH_MANY_QUERY: u64 = ENDPOINTS.len() as u64;
+const LIST_PAGINATED_QUERY: u64 = FETCH_MANY_QUERY + 1;
+const LIST_AFTER_QUERY: u64 = FETCH_MANY_QUERY + 2;
+const LIST_QUERY_QUERY: u64 = FETCH_MANY_QUERY + 3;
+const RELATION_QUERIES: u64 = FETCH_MANY_QUERY + 4;
+
+lazy_static! {
+    // The name of every model, its index is its ModelId in the client cache
+    pub static ref MODELS: Arc<RwLock<Vec<String>>> = Arc::new(RwLock::new(Vec::new()));
+}
+
+pub fn perform(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as ModelArgs);
     let mcall = parse_macro_input!(input as syn::ItemStruct);
 
     proc_macro::TokenStream::from(
-        impl_model_macro(table_name, mcall.clone())
-            .unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
+        impl_model_macro(args, mcall.clone()).unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
     )
 }
 
-fn impl_model_macro(
-    table_name: String,
-    mut item_struct: ItemStruct,
-) -> Result<proc_macro2::TokenStream> {
+fn impl_model_macro(args: ModelArgs, mut item_struct: ItemStruct) -> Result<proc_macro2::TokenStream> {
     let name = item_struct.ident.clone();
     // let name2 = ast.ident.clone();
 
-    crate::generate_migrations::register_migration(item_struct.clone());
+    let table = crate::generate_migrations::register_migration(item_struct.clone(), &args);
+
+    if cfg!(feature = "sqlite") {
+        let mut types = table
+            .id
+            .iter()
+            .map(|ty| ("id", ty))
+            .chain(table.columns.iter().map(|column| (column.name.as_str(), &column.ty)))
+            // The foreign keys added to the model are typed later, like the key of their parent
+            .filter(|(_, ty)| !ty.is_empty());
+
+        if let Some((column, ty)) = types.find(|(_, ty)| schema::sqlite_type(ty).is_none()) {
+            return Err(syn::Error::new(
+                name.span(),
+                format!("Cannot store the column {} in SQLite, {} is not supported", column, ty),
+            ));
+        }
+    }
+
+    let relations = schema::relations(&name, &item_struct.attrs)?;
+
+    item_struct.attrs.retain(|attr| !Relation::is_relation(attr));
+
+    // The foreign keys are added when not declared, with the type of the key of the parent
+    if let Fields::Named(fields) = &mut item_struct.fields {
+        for relation in relations.iter().filter(|r| r.kind == RelationKind::BelongsTo) {
+            let is_declared = fields
+                .named
+                .iter()
+                .any(|field| field.ident.as_ref().unwrap() == &relation.foreign_key);
+
+            if !is_declared {
+                let foreign_key: syn::Ident = syn::parse_str(&relation.foreign_key)?;
+                let model = &relation.model;
+                let declared: syn::FieldsNamed = syn::parse2(quote! {
+                    { pub #foreign_key: <#model as comet::prelude::PrimaryKey>::Key }
+                })?;
+
+                fields.named.extend(declared.named);
+            }
+        }
+
+        // After the foreign keys, like the columns of the table
+        for (timestamp, nullable) in schema::timestamp_fields(&args) {
+            let ident: syn::Ident = syn::parse_str(timestamp)?;
+
+            if let Some(field) = fields.named.iter().find(|field| field.ident.as_ref() == Some(&ident)) {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "This field is maintained by the model, remove it",
+                ));
+            }
+
+            let declared: syn::FieldsNamed = if nullable {
+                syn::parse2(quote! { { pub #ident: Option<chrono::NaiveDateTime> } })?
+            } else {
+                syn::parse2(quote! { { pub #ident: chrono::NaiveDateTime } })?
+            };
+
+            fields.named.extend(declared.named);
+        }
+    }
+
+    let timestamps = schema::timestamp_fields(&args)
+        .into_iter()
+        .map(|(timestamp, _)| syn::parse_str::<syn::Ident>(timestamp))
+        .collect::<Result<Vec<_>>>()?;
+
+    // The fields only on the client, the ones stored in another column, the natural key
+    // and the fields with a `#[default]`
+    let mut skipped = vec![];
+    let mut renamed = vec![];
+    let mut natural_key = vec![];
+    let mut defaulted = vec![];
+    let mut validations = vec![];
+
+    if let Fields::Named(fields) = &mut item_struct.fields {
+        for field in fields.named.iter_mut() {
+            let attrs = FieldAttrs::parse(field)?;
+            let ident = field.ident.clone().unwrap();
+
+            let mut field_validations = validate::parse(field)?;
+
+            if attrs.skip && !field_validations.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "A #[skip] field is not sent to the server, it can't be validated",
+                ));
+            }
+
+            // Checked before the database refuses it
+            if let (Some(len), false) = (attrs.max_len, attrs.skip) {
+                field_validations.push(validate::Validation::Length {
+                    min: None,
+                    max: Some(syn::parse_str(&len.to_string())?),
+                });
+            }
+
+            validations.push(validate::field_checks(field, &field_validations));
+
+            field.attrs.retain(|attr| !FieldAttrs::is_field_attr(attr) && !validate::is_validate_attr(attr));
+
+            if attrs.skip {
+                skipped.push(ident.clone());
+            }
+
+            if attrs.primary_key {
+                natural_key.push((ident.clone(), field.ty.clone()));
+            }
+
+            if attrs.default.is_some() {
+                defaulted.push(ident.clone());
+            }
+
+            if let Some(column) = attrs.column {
+                renamed.push((ident, syn::parse_str::<syn::Ident>(&column)?));
+            }
+        }
+    }
+
+    if let (Some(primary_key), false) = (&args.primary_key, natural_key.is_empty()) {
+        return Err(syn::Error::new_spanned(
+            primary_key,
+            "A model with #[primary_key] fields has no id",
+        ));
+    }
+
+    let id_type = schema::id_type(args.primary_key.as_ref())?;
+
+    // The id is given by the database, it is None until the model is saved
+    let is_serial = natural_key.is_empty() && id_type != "UUID";
+
+    let key_ty = match (id_type, &natural_key[..]) {
+        (_, [(_, ty)]) => quote! { #ty },
+        (_, [_, ..]) => {
+            let types = natural_key.iter().map(|(_, ty)| ty);
+
+            quote! { (#(#types),*) }
+        }
+        ("BIGSERIAL", _) => quote! { i64 },
+        ("UUID", _) => quote! { comet::prelude::uuid::Uuid },
+        _ => quote! { i32 },
+    };
+
+    let is_skipped = |field: &syn::Field| skipped.contains(field.ident.as_ref().unwrap());
+    let column_of_ident = |ident: &syn::Ident| {
+        renamed
+            .iter()
+            .find(|(renamed, _)| renamed == ident)
+            .map(|(_, column)| column.clone())
+    };
+    let column_of = |field: &syn::Field| column_of_ident(field.ident.as_ref().unwrap());
+    let is_defaulted = |field: &syn::Field| defaulted.contains(field.ident.as_ref().unwrap());
+
+    // The fields and columns of the primary key
+    let (key_fields, key_columns) = if natural_key.is_empty() {
+        let id: syn::Ident = syn::parse_str("id")?;
+
+        (vec![id.clone()], vec![id])
+    } else {
+        natural_key
+            .iter()
+            .map(|(ident, _)| {
+                let column = column_of_ident(ident).unwrap_or_else(|| ident.clone());
+
+                (ident.clone(), column)
+            })
+            .unzip()
+    };
+
+    let (client_id, server_id) = if !natural_key.is_empty() {
+        (None, None)
+    } else if is_serial {
+        (
+            Some(quote! { pub id: Option<#key_ty>, }),
+            Some(quote! {
+                #[diesel(deserialize_as = #key_ty)]
+                pub id: Option<#key_ty>,
+            }),
+        )
+    } else {
+        (Some(quote! { pub id: #key_ty, }), Some(quote! { pub id: #key_ty, }))
+    };
+
+    let key_values = key_fields.iter().map(|field| quote! { self.#field.clone() });
+
+    let primary_key_value = if is_serial {
+        quote! { self.id.clone() }
+    } else if natural_key.len() > 1 {
+        quote! { Some((#(#key_values),*)) }
+    } else {
+        quote! { Some(#(#key_values)*) }
+    };
 
     let fields = &item_struct.fields;
     let derives = item_struct.attrs.clone();
@@ -29,16 +236,34 @@ fn impl_model_macro(
         Fields::Named(fields) => {
             let named = &fields.named;
 
-            let lower_name = name.to_string().to_ascii_lowercase();
+            // The client-only fields are missing from what the server sends
+            let client_fields = named
+                .iter()
+                .map(|field| {
+                    let default = if is_skipped(field) {
+                        Some(quote! { #[serde(default)] })
+                    } else {
+                        None
+                    };
 
-            let lower_name_ident: syn::Ident = syn::parse_str(&lower_name).unwrap();
+                    quote! { #default #field }
+                })
+                .collect::<Vec<_>>();
 
-            // tricks to have a one-line iterator to create "impl #new_name_ident {}"
-            let mut lower_name_ident_vec = vec![];
+            let server_fields = named
+                .iter()
+                .zip(client_fields.iter())
+                .map(|(field, client_field)| match column_of(field) {
+                    Some(column) => quote! { #[diesel(column_name = #column)] #client_field },
+                    None => client_field.clone(),
+                })
+                .collect::<Vec<_>>();
 
-            for _ in &fields.named {
-                lower_name_ident_vec.push(lower_name_ident.clone());
-            }
+            let stored = named.iter().filter(|field| !is_skipped(field)).collect::<Vec<_>>();
+
+            let lower_name = name.to_string().to_ascii_lowercase();
+
+            let lower_name_ident: syn::Ident = syn::parse_str(&lower_name).unwrap();
 
             // tricks to have a one-line iterator to create "pub fn fetch_by_ {}"
             let mut name_vec = vec![];
@@ -52,11 +277,56 @@ fn impl_model_macro(
 
             let new_name_ident = item_struct.ident.clone();
 
-            let table_name = if table_name.len() == 0 {
-                lower_name.clone() + &"s"
-            } else {
-                table_name.replace("\"", "")
-            };
+            // The `NewX` struct only has the stored fields, and the id when it is not given by the database.
+            // The timestamps are set by the server.
+            // A `#[default]` field is an `Option`, left out of the insert when None so the database gives it its default
+            let mut new_fields: Vec<syn::Field> = stored
+                .iter()
+                .filter(|field| !timestamps.contains(field.ident.as_ref().unwrap()))
+                .map(|field| {
+                    let mut field = (*field).clone();
+
+                    if is_defaulted(&field) && schema::generic_inner(&field.ty, "Option").is_none() {
+                        let ty = &field.ty;
+
+                        field.ty = syn::parse_quote!(Option<#ty>);
+                    }
+
+                    field
+                })
+                .collect();
+
+            if !is_serial && natural_key.is_empty() {
+                let id: syn::FieldsNamed = syn::parse2(quote! { { pub id: #key_ty } })?;
+
+                new_fields.insert(0, id.named[0].clone());
+            }
+
+            let mut client_new_struct = item_struct.clone();
+            let mut server_new_struct = item_struct.clone();
+
+            if let (Fields::Named(client_new), Fields::Named(server_new)) =
+                (&mut client_new_struct.fields, &mut server_new_struct.fields)
+            {
+                client_new.named = new_fields.iter().cloned().collect();
+                server_new.named = new_fields
+                    .iter()
+                    .map(|field| {
+                        let mut field = field.clone();
+
+                        if let Some(column) = column_of(&field) {
+                            field.attrs.push(syn::parse_quote!(#[diesel(column_name = #column)]));
+                        }
+
+                        field
+                    })
+                    .collect();
+            }
+
+            let table_name = args
+                .table_name
+                .clone()
+                .unwrap_or_else(|| format!("{}s", lower_name));
 
             let table_name_ident: syn::Ident = syn::parse_str(&table_name).unwrap();
 
@@ -70,13 +340,974 @@ fn impl_model_macro(
             let from_name: syn::Ident =
                 syn::parse_str(&("from_".to_string() + &lower_name)).unwrap();
 
-            let mut fields_idents = vec![];
+            // The stored fields of `NewX`, and their value from the model
+            let written = stored
+                .iter()
+                .filter(|field| !timestamps.contains(field.ident.as_ref().unwrap()))
+                .collect::<Vec<_>>();
+
+            let written_idents = written
+                .iter()
+                .map(|field| field.ident.clone().unwrap())
+                .collect::<Vec<_>>();
+
+            let written_values = written
+                .iter()
+                .map(|field| {
+                    let ident = &field.ident;
+
+                    if is_defaulted(field) && schema::generic_inner(&field.ty, "Option").is_none() {
+                        quote! { Some(#lower_name_ident.#ident.clone()) }
+                    } else {
+                        quote! { #lower_name_ident.#ident.clone() }
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let new_id = if new_fields.len() > written.len() {
+                Some(quote! { id: #lower_name_ident.id.clone(), })
+            } else {
+                None
+            };
+
+            // What an update writes: every stored field, a None is written as NULL
+            let changes_of = |model: proc_macro2::TokenStream| {
+                let columns = written.iter().map(|field| {
+                    let column = column_of(field).unwrap_or_else(|| field.ident.clone().unwrap());
+
+                    quote! { #table_name_ident::dsl::#column }
+                });
+                let idents = &written_idents;
+
+                quote! { (#(#columns.eq(#model.#idents.clone()),)*) }
+            };
+
+            // The columns of the primary key, as a single expression
+            let key_paths = key_columns
+                .iter()
+                .map(|column| quote! { #table_name_ident::dsl::#column })
+                .collect::<Vec<_>>();
+
+            let key_columns_expr = if key_paths.len() > 1 {
+                quote! { (#(#key_paths),*) }
+            } else {
+                quote! { #(#key_paths)* }
+            };
+
+            // The natural key is made of regular columns
+            let key_attr = if natural_key.is_empty() {
+                None
+            } else {
+                let key_columns = &key_columns;
+
+                Some(quote! { #[diesel(primary_key(#(#key_columns),*))] })
+            };
+
+            // The deleted rows are never loaded nor updated
+            let live = if args.soft_delete {
+                quote! { .filter(#table_name_ident::dsl::deleted_at.is_null()) }
+            } else {
+                quote! {}
+            };
+
+            // Every write updates `updated_at`, and an upsert brings a deleted row back
+            let mut touched = vec![];
+
+            if args.timestamps {
+                touched.push(quote! { #table_name_ident::dsl::updated_at.eq(diesel::dsl::now) });
+            }
+
+            let changeset = if touched.is_empty() {
+                quote! { changes }
+            } else {
+                let touched = &touched;
+
+                quote! { (changes, #(#touched),*) }
+            };
+
+            if args.soft_delete {
+                touched.push(quote! {
+                    #table_name_ident::dsl::deleted_at.eq(None::<chrono::NaiveDateTime>)
+                });
+            }
+
+            let upsert_changeset = if touched.is_empty() {
+                quote! { changes }
+            } else {
+                quote! { (changes, #(#touched),*) }
+            };
+
+            let model_changes = changes_of(quote! { #lower_name_ident });
+            let self_changes = changes_of(quote! { self });
+
+            // An unsaved model matches no row
+            let identifiable = if is_serial {
+                quote! {
+                    impl diesel::associations::HasTable for #name {
+                        type Table = #table_name_ident::table;
+
+                        fn table() -> Self::Table {
+                            #table_name_ident::table
+                        }
+                    }
+
+                    impl<'a> diesel::associations::Identifiable for &'a #name {
+                        type Id = &'a #key_ty;
+
+                        fn id(self) -> Self::Id {
+                            self.id.as_ref().unwrap_or(&0)
+                        }
+                    }
+                }
+            } else {
+                quote! {}
+            };
+
+            let identifiable_derive = if is_serial {
+                None
+            } else {
+                Some(quote! { Identifiable, })
+            };
+
+            // The derived Queryable would expect a column for the client-only fields
+            let (server_derives, queryable) = if skipped.is_empty() {
+                (
+                    quote! { #[derive(#identifiable_derive Serialize, Deserialize, Queryable, Clone, AsChangeset)] },
+                    None,
+                )
+            } else {
+                let (id_type, id_binding, id_value) = if !natural_key.is_empty() {
+                    (None, None, None)
+                } else if is_serial {
+                    (Some(quote! { #key_ty, }), Some(quote! { id, }), Some(quote! { id: Some(id), }))
+                } else {
+                    (Some(quote! { #key_ty, }), Some(quote! { id, }), Some(quote! { id, }))
+                };
+
+                let row_types = stored.iter().map(|field| &field.ty).collect::<Vec<_>>();
+                let row_types2 = row_types.clone();
+                let id_type2 = id_type.clone();
+                let stored_idents = stored
+                    .iter()
+                    .map(|field| field.ident.clone().unwrap())
+                    .collect::<Vec<_>>();
+                let stored_idents2 = stored_idents.clone();
+                let skipped_idents = &skipped;
+
+                (
+                    quote! { #[derive(#identifiable_derive Serialize, Deserialize, Clone)] },
+                    Some(quote! {
+                        impl<__ST, __DB> diesel::deserialize::Queryable<__ST, __DB> for #name
+                        where
+                            __DB: diesel::backend::Backend,
+                            (#id_type #(#row_types,)*): diesel::deserialize::FromStaticSqlRow<__ST, __DB>,
+                        {
+                            type Row = (#id_type2 #(#row_types2,)*);
+
+                            fn build(row: Self::Row) -> diesel::deserialize::Result<Self> {
+                                let (#id_binding #(#stored_idents,)*) = row;
+
+                                Ok(#name {
+                                    #id_value
+                                    #(#stored_idents2,)*
+                                    #(#skipped_idents: Default::default(),)*
+                                })
+                            }
+                        }
+                    }),
+                )
+            };
+
+            // SQLite has no trigger to notify the watched queries, the writes do it themselves
+            let notify = if cfg!(feature = "sqlite") {
+                quote! { .map(|res| { comet::prelude::table_changed(#table_name); res }) }
+            } else {
+                quote! {}
+            };
+
+            let create_endpoint = quote! {
+                pub async fn create(&self) -> std::result::Result<#name, comet::prelude::DbError> {
+                    use crate::schema::#table_name_ident;
+
+                    comet::prelude::Validate::validate(self)?;
+
+                    let #lower_name_ident = #new_name_ident::#from_name(self);
+
+                    comet::prelude::with_connection(move |conn| {
+                        diesel::insert_into(#table_name_ident::table)
+                            .values(#lower_name_ident)
+                            .get_result::<#name>(conn).map_err(comet::prelude::DbError::from)
+                    })
+                    .await
+                    #notify
+                }
+            };
+
+            let list_endpoint = quote! {
+                pub async fn list() -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                    use crate::schema::#table_name_ident;
+
+                    comet::prelude::with_connection(move |conn| {
+                        #table_name_ident::table
+                            #live
+                            .order(#key_columns_expr)
+                            .load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                    })
+                    .await
+                }
+            };
+
+            let update_endpoint = quote! {
+                pub async fn update(id_given: #key_ty, #lower_name_ident: #name) -> std::result::Result<usize, comet::prelude::DbError> {
+                    use crate::schema::#table_name_ident;
+
+                    comet::prelude::Validate::validate(&#lower_name_ident)?;
+
+                    let changes = #model_changes;
+
+                    comet::prelude::with_connection(move |conn| {
+                        diesel::update(#table_name_ident::table.find(id_given) #live)
+                            .set(#changeset)
+                            .execute(conn).map_err(comet::prelude::DbError::from)
+                    })
+                    .await
+                    #notify
+                }
+            };
+
+            // A key not given by the database is known before the first save, the row is upserted
+            let save_endpoint = if is_serial {
+                // Given by the database with the id
+                let created = timestamps.iter().filter(|ident| *ident != "deleted_at").collect::<Vec<_>>();
+                let created2 = created.clone();
+
+                quote! {
+                    pub async fn save(&mut self) -> std::result::Result<(), comet::prelude::DbError> {
+                        match self.id {
+                            None => {
+                                let res = self.create().await?;
+
+                                self.id = res.id;
+                                #(self.#created = res.#created2;)*
+                            }
+                            Some(id) => {
+                                #name::update(id, self.clone()).await?;
+                            }
+                        }
+
+                        Ok(())
+                    }
+                }
+            } else {
+                quote! {
+                    pub async fn save(&mut self) -> std::result::Result<(), comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
+
+                        comet::prelude::Validate::validate(self)?;
+
+                        let #lower_name_ident = #new_name_ident::#from_name(self);
+                        let changes = #self_changes;
+
+                        comet::prelude::with_connection(move |conn| {
+                            diesel::insert_into(#table_name_ident::table)
+                                .values(&#lower_name_ident)
+                                .on_conflict(#key_columns_expr)
+                                .do_update()
+                                .set(#upsert_changeset)
+                                .execute(conn).map_err(comet::prelude::DbError::from)?;
+
+                            Ok(())
+                        })
+                        .await
+                        #notify
+                    }
+                }
+            };
+
+            let delete_endpoint = if args.soft_delete {
+                quote! {
+                    pub async fn delete(id_given: #key_ty) -> std::result::Result<usize, comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
 
-            for field in &fields.named {
-                fields_idents.push(field.ident.clone().unwrap());
+                        comet::prelude::with_connection(move |conn| {
+                            diesel::update(#table_name_ident::table.find(id_given) #live)
+                                .set(#table_name_ident::dsl::deleted_at.eq(diesel::dsl::now))
+                                .execute(conn).map_err(comet::prelude::DbError::from)
+                        })
+                        .await
+                        #notify
+                    }
+                }
+            } else {
+                quote! {
+                    pub async fn delete(id_given: #key_ty) -> std::result::Result<usize, comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
+
+                        comet::prelude::with_connection(move |conn| {
+                            diesel::delete(#table_name_ident::table.find(id_given))
+                                .execute(conn).map_err(comet::prelude::DbError::from)
+                        })
+                        .await
+                        #notify
+                    }
+                }
+            };
+
+            let fetch_endpoint = quote! {
+                pub async fn fetch(id_given: #key_ty) -> std::result::Result<#name, comet::prelude::DbError> {
+                    use crate::schema::#table_name_ident;
+
+                    comet::prelude::with_connection(move |conn| {
+                        #table_name_ident::table.find(id_given) #live .first::<#name>(conn).map_err(comet::prelude::DbError::from)
+                    })
+                    .await
+                }
+            };
+
+            let policy_helper = args.policy.as_ref().map(|policy| {
+                // Without an id telling it, an upserted model is new when its row doesn't exist yet
+                let save_rule = if is_serial {
+                    quote! {
+                        match &#lower_name_ident.id {
+                            None => <#policy as comet::prelude::Policy<#name, #key_ty>>::can_create(context, #lower_name_ident).await,
+                            Some(id) => #name::__policy_can_update(context, id, #lower_name_ident).await,
+                        }
+                    }
+                } else {
+                    quote! {
+                        let key = comet::prelude::PrimaryKey::primary_key(#lower_name_ident).unwrap();
+
+                        match #name::fetch(key.clone()).await {
+                            Ok(stored) => {
+                                <#policy as comet::prelude::Policy<#name, #key_ty>>::can_update(context, &key, &stored, #lower_name_ident).await
+                            }
+                            Err(comet::prelude::DbError::NotFound) => {
+                                <#policy as comet::prelude::Policy<#name, #key_ty>>::can_create(context, #lower_name_ident).await
+                            }
+                            Err(_) => false,
+                        }
+                    }
+                };
+
+                quote! {
+                    impl #name {
+                        #[doc(hidden)]
+                        pub async fn __policy_can_save(
+                            context: &comet::prelude::RequestContext,
+                            #lower_name_ident: &#name,
+                        ) -> bool {
+                            #save_rule
+                        }
+
+                        // The rules judge the row as stored, and the values the client wants to write instead.
+                        // A row that can't be loaded is refused
+                        #[doc(hidden)]
+                        pub async fn __policy_can_update(
+                            context: &comet::prelude::RequestContext,
+                            id: &#key_ty,
+                            sent: &#name,
+                        ) -> bool {
+                            match #name::fetch(id.clone()).await {
+                                Ok(stored) => <#policy as comet::prelude::Policy<#name, #key_ty>>::can_update(context, id, &stored, sent).await,
+                                Err(_) => false,
+                            }
+                        }
+
+                        #[doc(hidden)]
+                        pub async fn __policy_can_delete(
+                            context: &comet::prelude::RequestContext,
+                            id: &#key_ty,
+                        ) -> bool {
+                            match #name::fetch(id.clone()).await {
+                                Ok(stored) => <#policy as comet::prelude::Policy<#name, #key_ty>>::can_delete(context, id, &stored).await,
+                                Err(_) => false,
+                            }
+                        }
+
+                        #[doc(hidden)]
+                        pub async fn __policy_can_fetch_many(
+                            context: &comet::prelude::RequestContext,
+                            ids: &Vec<#key_ty>,
+                        ) -> bool {
+                            for id in ids {
+                                if !<#policy as comet::prelude::Policy<#name, #key_ty>>::can_fetch(context, id).await {
+                                    return false;
+                                }
+                            }
+
+                            true
+                        }
+
+                        #[doc(hidden)]
+                        pub async fn __policy_can_list_by<T: Sync>(
+                            context: &comet::prelude::RequestContext,
+                            _ids: &Vec<T>,
+                        ) -> bool {
+                            <#policy as comet::prelude::Policy<#name, #key_ty>>::can_list(context).await
+                        }
+
+                        #[doc(hidden)]
+                        pub async fn __policy_can_list_page<A: Sync, B: Sync>(
+                            context: &comet::prelude::RequestContext,
+                            _from: &A,
+                            _limit: &B,
+                        ) -> bool {
+                            <#policy as comet::prelude::Policy<#name, #key_ty>>::can_list(context).await
+                        }
+
+                        #[doc(hidden)]
+                        pub async fn __policy_can_list_query(
+                            context: &comet::prelude::RequestContext,
+                            _query: &comet::prelude::ModelQuery<#name>,
+                        ) -> bool {
+                            <#policy as comet::prelude::Policy<#name, #key_ty>>::can_list(context).await
+                        }
+                    }
+                }
+            });
+
+            // Also checked before watching a `#[watch]` query of the model
+            let list_rule = match &args.policy {
+                Some(policy) => quote! { <#policy as comet::prelude::Policy<#name, #key_ty>>::can_list(_context).await },
+                None => quote! { true },
+            };
+
+            let model_id = {
+                let mut models = MODELS.write().unwrap();
+
+                models.push(name.to_string());
+
+                (models.len() - 1) as u64
+            };
+
+            let mut enabled_endpoints = vec![];
+            let mut disabled_endpoints = vec![];
+            let mut server_wrappers = vec![];
+            let mut client_wrappers = vec![];
+
+            for (endpoint, tokens) in ENDPOINTS.iter().zip(vec![
+                create_endpoint,
+                list_endpoint,
+                update_endpoint,
+                save_endpoint,
+                delete_endpoint,
+                fetch_endpoint,
+            ]) {
+                if args.disabled.iter().any(|disabled| disabled == endpoint) {
+                    disabled_endpoints.push(tokens);
+
+                    continue;
+                }
+
+                let guard = args.policy.as_ref().map(|policy| {
+                    let rule: syn::Ident = syn::parse_str(&format!("can_{}", endpoint)).unwrap();
+
+                    match *endpoint {
+                        "save" | "update" | "delete" => {
+                            let helper: syn::Ident = syn::parse_str(&format!("__policy_can_{}", endpoint)).unwrap();
+
+                            quote! { #[endpoint_guard(#name::#helper)] }
+                        }
+                        _ => quote! { #[endpoint_guard(<#policy as comet::prelude::Policy<#name, #key_ty>>::#rule)] },
+                    }
+                });
+
+                // The RPC is hidden behind a method of the same signature,
+                // that goes through the client cache
+                let mut method: syn::ImplItemMethod = syn::parse2(tokens)?;
+                let rpc_name: syn::Ident = syn::parse_str(&format!("__{}", endpoint)).unwrap();
+
+                server_wrappers.push(delegate(&method, &rpc_name));
+                client_wrappers.push(cached_endpoint(
+                    endpoint,
+                    args.optimistic,
+                    is_serial,
+                    &name,
+                    &lower_name_ident,
+                    model_id,
+                    &method,
+                ));
+
+                method.sig.ident = rpc_name;
+
+                // The mutations wait in the offline queue
+                let queue = if ["create", "save", "delete"].contains(endpoint) {
+                    Some(quote! { #[queue] })
+                } else {
+                    None
+                };
+
+                enabled_endpoints.push(quote! {
+                    #guard
+                    #queue
+                    #[doc(hidden)]
+                    #method
+                });
             }
 
-            let fields_idents2 = fields_idents.clone();
+            let is_enabled = |endpoint: &str| !args.disabled.iter().any(|disabled| disabled == endpoint);
+
+            // Loads many models at once, for the relations of other models
+            if is_enabled("fetch") && key_columns.len() == 1 {
+                let guard = args.policy.as_ref().map(|_| {
+                    quote! { #[endpoint_guard(#name::__policy_can_fetch_many)] }
+                });
+
+                let method: syn::ImplItemMethod = syn::parse2(quote! {
+                    pub async fn fetch_many(ids: Vec<#key_ty>) -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
+
+                        comet::prelude::with_connection(move |conn| {
+                            #table_name_ident::table
+                                #live
+                                .filter(#key_columns_expr.eq_any(ids))
+                                .order(#key_columns_expr)
+                                .load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                        })
+                        .await
+                    }
+                })?;
+
+                push_query(
+                    method,
+                    guard,
+                    FETCH_MANY_QUERY,
+                    model_id,
+                    &mut enabled_endpoints,
+                    &mut server_wrappers,
+                    &mut client_wrappers,
+                );
+            }
+
+            // The columns that can be filtered and sorted on, by field name
+            let custom = CUSTOM_TYPES.read().unwrap().clone();
+
+            let mut query_columns = vec![];
+
+            if client_id.is_some() {
+                let id: syn::Ident = syn::parse_str("id")?;
+
+                query_columns.push((id.clone(), id, key_ty.clone(), false));
+            }
+
+            for field in stored.iter().filter(|field| schema::is_comparable(&field.ty, &custom)) {
+                let ident = field.ident.clone().unwrap();
+                let column = column_of(field).unwrap_or_else(|| ident.clone());
+                let ty = &field.ty;
+                let inner = schema::generic_inner(ty, "Option").unwrap_or_else(|| ty.clone());
+                let is_text = match &inner {
+                    syn::Type::Path(path) => path.path.is_ident("String"),
+                    _ => false,
+                };
+
+                query_columns.push((ident, column, quote! { #ty }, is_text));
+            }
+
+            let mut query_methods = vec![];
+
+            if is_enabled("list") {
+                let page_guard = args.policy.as_ref().map(|_| {
+                    quote! { #[endpoint_guard(#name::__policy_can_list_page)] }
+                });
+
+                // Pages start at 1
+                let method: syn::ImplItemMethod = syn::parse2(quote! {
+                    pub async fn list_paginated(page: i64, per_page: i64) -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
+
+                        if page < 1 || per_page < 1 {
+                            return Err(comet::prelude::DbError::Other("Invalid page".to_string()));
+                        }
+
+                        if per_page > comet::prelude::MAX_LIMIT {
+                            return Err(comet::prelude::DbError::Other(format!("A page can't hold more than {}", comet::prelude::MAX_LIMIT)));
+                        }
+
+                        comet::prelude::with_connection(move |conn| {
+                            #table_name_ident::table
+                                #live
+                                .order(#key_columns_expr)
+                                .limit(per_page)
+                                .offset((page - 1) * per_page)
+                                .load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                        })
+                        .await
+                    }
+                })?;
+
+                push_query(
+                    method,
+                    page_guard.clone(),
+                    LIST_PAGINATED_QUERY,
+                    model_id,
+                    &mut enabled_endpoints,
+                    &mut server_wrappers,
+                    &mut client_wrappers,
+                );
+
+                // The models after the key of the last one already loaded, stable when rows are added
+                if key_columns.len() == 1 {
+                    let method: syn::ImplItemMethod = syn::parse2(quote! {
+                        pub async fn list_after(
+                            cursor: Option<#key_ty>,
+                            limit: i64,
+                        ) -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                            use crate::schema::#table_name_ident;
+
+                            if limit < 1 {
+                                return Err(comet::prelude::DbError::Other("Invalid limit".to_string()));
+                            }
+
+                            if limit > comet::prelude::MAX_LIMIT {
+                                return Err(comet::prelude::DbError::Other(format!("The limit can't be more than {}", comet::prelude::MAX_LIMIT)));
+                            }
+
+                            comet::prelude::with_connection(move |conn| {
+                                let mut statement = #table_name_ident::table
+                                    #live
+                                    .order(#key_columns_expr)
+                                    .limit(limit)
+                                    .into_boxed();
+
+                                if let Some(cursor) = cursor {
+                                    statement = statement.filter(#key_columns_expr.gt(cursor));
+                                }
+
+                                statement.load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                            })
+                            .await
+                        }
+                    })?;
+
+                    push_query(
+                        method,
+                        page_guard,
+                        LIST_AFTER_QUERY,
+                        model_id,
+                        &mut enabled_endpoints,
+                        &mut server_wrappers,
+                        &mut client_wrappers,
+                    );
+                }
+
+                let query_guard = args.policy.as_ref().map(|_| {
+                    quote! { #[endpoint_guard(#name::__policy_can_list_query)] }
+                });
+
+                let filter_arms = query_columns.iter().map(|(ident, column, ty, is_text)| {
+                    let field = ident.to_string();
+                    let like = if *is_text {
+                        quote! { statement.filter(column.like(filter.value::<#ty>()?)) }
+                    } else {
+                        quote! { return Err(comet::prelude::DbError::Other(format!("The column {} is not text", filter.column))) }
+                    };
+
+                    quote! {
+                        #field => {
+                            let column = #table_name_ident::dsl::#column;
+
+                            match filter.op {
+                                FilterOp::Eq => statement.filter(column.eq(filter.value::<#ty>()?)),
+                                FilterOp::Ne => statement.filter(column.ne(filter.value::<#ty>()?)),
+                                FilterOp::Lt => statement.filter(column.lt(filter.value::<#ty>()?)),
+                                FilterOp::Le => statement.filter(column.le(filter.value::<#ty>()?)),
+                                FilterOp::Gt => statement.filter(column.gt(filter.value::<#ty>()?)),
+                                FilterOp::Ge => statement.filter(column.ge(filter.value::<#ty>()?)),
+                                FilterOp::Like => #like,
+                                FilterOp::IsNull => statement.filter(column.is_null()),
+                                FilterOp::IsNotNull => statement.filter(column.is_not_null()),
+                            }
+                        }
+                    }
+                });
+
+                let order_arms = query_columns.iter().map(|(ident, column, _, _)| {
+                    let field = ident.to_string();
+
+                    quote! {
+                        (#field, false) => statement.then_order_by(#table_name_ident::dsl::#column.asc()),
+                        (#field, true) => statement.then_order_by(#table_name_ident::dsl::#column.desc()),
+                    }
+                });
+
+                // The columns are checked against the model, the values against their type
+                let method: syn::ImplItemMethod = syn::parse2(quote! {
+                    pub async fn list_query(
+                        query: comet::prelude::ModelQuery<#name>,
+                    ) -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                        use crate::schema::#table_name_ident;
+                        use comet::prelude::FilterOp;
+
+                        query.check_limits()?;
+
+                        comet::prelude::with_connection(move |conn| {
+                            let mut statement = #table_name_ident::table #live .into_boxed();
+
+                            for filter in &query.filters {
+                                statement = match filter.column.as_str() {
+                                    #(#filter_arms)*
+                                    column => return Err(comet::prelude::DbError::Other(format!("Unknown column {}", column))),
+                                };
+                            }
+
+                            for order in &query.order {
+                                statement = match (order.column.as_str(), order.desc) {
+                                    #(#order_arms)*
+                                    (column, _) => return Err(comet::prelude::DbError::Other(format!("Unknown column {}", column))),
+                                };
+                            }
+
+                            statement = statement.then_order_by(#key_columns_expr);
+
+                            statement = statement.limit(query.effective_limit());
+
+                            if let Some(offset) = query.offset {
+                                statement = statement.offset(offset);
+                            }
+
+                            statement.load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                        })
+                        .await
+                    }
+                })?;
+
+                push_query(
+                    method,
+                    query_guard,
+                    LIST_QUERY_QUERY,
+                    model_id,
+                    &mut enabled_endpoints,
+                    &mut server_wrappers,
+                    &mut client_wrappers,
+                );
+
+                let columns_mod: syn::Ident = syn::parse_str(&format!("{}_columns", lower_name))?;
+                let column_consts = query_columns.iter().map(|(ident, _, ty, _)| {
+                    let field = ident.to_string();
+
+                    quote! {
+                        pub const #ident: comet::prelude::ModelColumn<#name, #ty> = comet::prelude::ModelColumn::new(#field);
+                    }
+                });
+
+                query_methods.push(quote! {
+                    impl #name {
+                        pub fn query() -> comet::prelude::ModelQuery<#name> {
+                            comet::prelude::ModelQuery::new()
+                        }
+                    }
+
+                    #[cfg_attr(target_arch = "wasm32", comet::prelude::async_trait(?Send))]
+                    #[cfg_attr(not(target_arch = "wasm32"), comet::prelude::async_trait)]
+                    impl comet::prelude::QueryLoad for #name {
+                        async fn load_query(
+                            query: comet::prelude::ModelQuery<Self>,
+                        ) -> std::result::Result<Vec<Self>, comet::prelude::DbError> {
+                            #name::list_query(query).await
+                        }
+                    }
+
+                    // The columns of the model, to build its queries
+                    #[allow(non_upper_case_globals)]
+                    pub mod #columns_mod {
+                        use super::*;
+
+                        #(#column_consts)*
+                    }
+                });
+            }
+
+            let mut relation_methods = vec![];
+            let mut associations = vec![];
+
+            for (i, relation) in relations.iter().enumerate() {
+                let model = &relation.model;
+                let foreign_key: syn::Ident = syn::parse_str(&relation.foreign_key)?;
+                let foreign_key_column = renamed
+                    .iter()
+                    .find(|(ident, _)| *ident == foreign_key)
+                    .map(|(_, column)| column.clone())
+                    .unwrap_or_else(|| foreign_key.clone());
+                let list_by: syn::Ident = syn::parse_str(&format!("list_by_{}", relation.foreign_key))?;
+
+                match relation.kind {
+                    RelationKind::BelongsTo => {
+                        let parent: syn::Ident = syn::parse_str(&relation.lower_model())?;
+                        let with_parent: syn::Ident =
+                            syn::parse_str(&format!("with_{}", relation.lower_model()))?;
+
+                        associations.push(quote! {
+                            #[diesel(belongs_to(#model, foreign_key = #foreign_key_column))]
+                        });
+
+                        // The type of the foreign key, without its Option
+                        let foreign_key_field = match &item_struct.fields {
+                            Fields::Named(fields) => fields
+                                .named
+                                .iter()
+                                .find(|field| field.ident.as_ref().unwrap() == &relation.foreign_key)
+                                .map(|field| field.ty.clone()),
+                            _ => None,
+                        }
+                        .unwrap();
+
+                        let (foreign_key_ty, is_nullable) =
+                            match schema::generic_inner(&foreign_key_field, "Option") {
+                                Some(inner) => (inner, true),
+                                None => (foreign_key_field, false),
+                            };
+
+                        // The models belonging to any of the given ones
+                        if is_enabled("list") {
+                            let guard = args.policy.as_ref().map(|_| {
+                                quote! { #[endpoint_guard(#name::__policy_can_list_by)] }
+                            });
+
+                            let method: syn::ImplItemMethod = syn::parse2(quote! {
+                                pub async fn #list_by(ids: Vec<#foreign_key_ty>) -> std::result::Result<Vec<#name>, comet::prelude::DbError> {
+                                    use crate::schema::#table_name_ident;
+
+                                    comet::prelude::with_connection(move |conn| {
+                                        #table_name_ident::table
+                                            #live
+                                            .filter(#table_name_ident::dsl::#foreign_key_column.eq_any(ids))
+                                            .order(#key_columns_expr)
+                                            .load::<#name>(conn).map_err(comet::prelude::DbError::from)
+                                    })
+                                    .await
+                                }
+                            })?;
+
+                            push_query(
+                                method,
+                                guard,
+                                RELATION_QUERIES + i as u64,
+                                model_id,
+                                &mut enabled_endpoints,
+                                &mut server_wrappers,
+                                &mut client_wrappers,
+                            );
+                        }
+
+                        if is_nullable {
+                            relation_methods.push(quote! {
+                                pub async fn #parent(&self) -> std::result::Result<Option<#model>, comet::prelude::DbError> {
+                                    match self.#foreign_key.clone() {
+                                        Some(id) => #model::fetch(id).await.map(Some),
+                                        None => Ok(None),
+                                    }
+                                }
+
+                                // Every parent is fetched at once
+                                pub async fn #with_parent(
+                                    models: Vec<#name>,
+                                ) -> std::result::Result<Vec<(#name, Option<#model>)>, comet::prelude::DbError> {
+                                    let mut ids = models.iter().filter_map(|model| model.#foreign_key.clone()).collect::<Vec<_>>();
+
+                                    ids.sort_unstable();
+                                    ids.dedup();
+
+                                    let parents = #model::fetch_many(ids).await?;
+
+                                    Ok(models
+                                        .into_iter()
+                                        .map(|model| {
+                                            let parent = model.#foreign_key.clone().and_then(|id| {
+                                                parents
+                                                    .iter()
+                                                    .find(|parent| comet::prelude::PrimaryKey::primary_key(*parent) == Some(id.clone()))
+                                                    .cloned()
+                                            });
+
+                                            (model, parent)
+                                        })
+                                        .collect())
+                                }
+                            });
+                        } else {
+                            relation_methods.push(quote! {
+                                pub async fn #parent(&self) -> std::result::Result<#model, comet::prelude::DbError> {
+                                    #model::fetch(self.#foreign_key.clone()).await
+                                }
+
+                                // Every parent is fetched at once.
+                                // A parent deleted since the models were loaded is `None`, its children are still there
+                                pub async fn #with_parent(
+                                    models: Vec<#name>,
+                                ) -> std::result::Result<Vec<(#name, Option<#model>)>, comet::prelude::DbError> {
+                                    let mut ids = models.iter().map(|model| model.#foreign_key.clone()).collect::<Vec<_>>();
+
+                                    ids.sort_unstable();
+                                    ids.dedup();
+
+                                    let parents = #model::fetch_many(ids).await?;
+
+                                    Ok(models
+                                        .into_iter()
+                                        .map(|model| {
+                                            let parent = parents
+                                                .iter()
+                                                .find(|parent| comet::prelude::PrimaryKey::primary_key(*parent) == Some(model.#foreign_key.clone()))
+                                                .cloned();
+
+                                            (model, parent)
+                                        })
+                                        .collect())
+                                }
+                            });
+                        }
+                    }
+                    RelationKind::HasMany => {
+                        let children: syn::Ident = syn::parse_str(&format!("{}s", relation.lower_model()))?;
+                        let with_children: syn::Ident =
+                            syn::parse_str(&format!("with_{}s", relation.lower_model()))?;
+
+                        // Relies on the `#[belongs_to]` of the other model
+                        relation_methods.push(quote! {
+                            pub async fn #children(&self) -> std::result::Result<Vec<#model>, comet::prelude::DbError> {
+                                #model::#list_by(comet::prelude::PrimaryKey::primary_key(self).into_iter().collect()).await
+                            }
+
+                            // Every child is fetched at once
+                            pub async fn #with_children(
+                                models: Vec<#name>,
+                            ) -> std::result::Result<Vec<(#name, Vec<#model>)>, comet::prelude::DbError> {
+                                let ids = models
+                                    .iter()
+                                    .filter_map(comet::prelude::PrimaryKey::primary_key)
+                                    .collect::<Vec<_>>();
+
+                                let children = #model::#list_by(ids).await?;
+
+                                Ok(models
+                                    .into_iter()
+                                    .map(|model| {
+                                        let owned = children
+                                            .iter()
+                                            .filter(|child| {
+                                                std::convert::Into::<Option<_>>::into(child.#foreign_key.clone())
+                                                    == comet::prelude::PrimaryKey::primary_key(&model)
+                                            })
+                                            .cloned()
+                                            .collect();
+
+                                        (model, owned)
+                                    })
+                                    .collect())
+                            }
+                        });
+                    }
+                }
+            }
+
+            let associations = if associations.is_empty() {
+                quote! {}
+            } else {
+                quote! {
+                    #[derive(Associations)]
+                    #(#associations)*
+                }
+            };
 
             let res = quote!(
                 #[cfg(target_arch = "wasm32")]
@@ -87,14 +1318,14 @@ fn impl_model_macro(
                     #[derive(Serialize, Deserialize, Clone)]
                     #[serde(crate = "comet::prelude::serde")] // must be below the derive attribute
                     pub struct #name {
-                        pub id: i32,
-                        #named
+                        #client_id
+                        #(#client_fields,)*
                     }
 
                     // #(#derives2)*
                     #[derive(Clone, Serialize, Deserialize)]
                     #[serde(crate = "comet::prelude::serde")] // must be below the derive attribute
-                    #item_struct
+                    #client_new_struct
                 }
 
                 #[cfg(target_arch = "wasm32")]
@@ -108,27 +1339,54 @@ fn impl_model_macro(
                     use crate::schema::#table_name_ident;
 
                     #(#derives2)*
-                    #[derive(Identifiable, Serialize, Deserialize, Queryable, Clone, AsChangeset)]
+                    #server_derives
+                    #associations
+                    #key_attr
                     #[serde(crate = "comet::prelude::serde")] // must be below the derive attribute
-                    // #[diesel(table_name = #table_name)]
+                    #[diesel(table_name = #table_name_ident)]
                     #[diesel(treat_none_as_null = true)]
                     pub struct #name {
-                        pub id: i32,
-                        #named
+                        #server_id
+                        #(#server_fields,)*
                     }
 
-                    #[derive(Insertable, Clone, Serialize, Deserialize, AsChangeset)]
+                    #queryable
+
+                    #identifiable
+
+                    #[derive(Insertable, Clone, Serialize, Deserialize)]
                     #[serde(crate = "comet::prelude::serde")] // must be below the derive attribute
                     #[diesel(table_name = #table_name_ident)]
-                    #[diesel(treat_none_as_null = true)]
-                    #item_struct
+                    #server_new_struct
+
+                    #policy_helper
+
+                    impl #name {
+                        #[doc(hidden)]
+                        pub async fn __policy_can_list(_context: &comet::prelude::RequestContext) -> bool {
+                            #list_rule
+                        }
+                    }
 
                     impl #new_name_ident {
                         pub fn #from_name(#lower_name_ident: &#name) -> #new_name_ident {
                             #new_name_ident {
-                                #(#fields_idents: #lower_name_ident_vec.#fields_idents2.clone()),*
+                                #new_id
+                                #(#written_idents: #written_values),*
                             }
                         }
+
+                        // Insert the row as is, without validation nor policy.
+                        // The `#[default]` fields left to None get the default of their column
+                        pub async fn create(self) -> std::result::Result<#name, comet::prelude::DbError> {
+                            comet::prelude::with_connection(move |conn| {
+                                diesel::insert_into(#table_name_ident::table)
+                                    .values(self)
+                                    .get_result::<#name>(conn).map_err(comet::prelude::DbError::from)
+                            })
+                            .await
+                            #notify
+                        }
                     }
                 }
 
@@ -136,86 +1394,406 @@ fn impl_model_macro(
 
                 #[rpc]
                 impl #name {
-                    pub async fn create(&self) -> std::result::Result<#name, String> {
-                        use crate::schema::#table_name_ident;
+                    #(#enabled_endpoints)*
+                }
 
-                        let mut conn = crate::establish_connection();
+                // The disabled endpoints are still usable by the server code, but are not part of the protocol
+                #[cfg(not(target_arch = "wasm32"))]
+                impl #name {
+                    #(#disabled_endpoints)*
 
-                        let #lower_name_ident = #new_name_ident::#from_name(self);
+                    #(#server_wrappers)*
+                }
 
-                        diesel::insert_into(#table_name_ident::table)
-                            .values(#lower_name_ident)
-                            .execute(&mut conn).map_err(|e| "Error insert".to_string())?;
+                #[cfg(target_arch = "wasm32")]
+                impl #name {
+                    #(#client_wrappers)*
+                }
 
-                        #table_name_ident::table
-                            .order(#table_name_ident::dsl::id.desc())
-                            .first(&mut conn).map_err(|e| "Error create".to_string())
-                    }
+                impl #name {
+                    // The table of the model, named by `#[model(table = "...")]` or after the model
+                    pub const TABLE: &'static str = #table_name;
 
-                    pub async fn list() -> std::result::Result<Vec<#name>, String> {
-                        use crate::schema::#table_name_ident;
+                    #(#relation_methods)*
+                }
 
-                        let mut conn = crate::establish_connection();
+                #(#query_methods)*
 
+                impl comet::prelude::Validate for #name {
+                    #[allow(unused_mut)]
+                    fn validate(&self) -> std::result::Result<(), comet::prelude::ValidationErrors> {
+                        let mut errors = comet::prelude::ValidationErrors::new();
 
-                        #table_name_ident::table
-                            .order(#table_name_ident::dsl::id)
-                            .load::<#name>(&mut conn).map_err(|e| "Error list".to_string())
+                        #(#validations)*
+
+                        errors.into_result()
                     }
+                }
 
-                    pub async fn update(id_given: i32, #lower_name_ident: #name) -> std::result::Result<usize, String> {
-                        use crate::schema::#table_name_ident;
+                impl comet::prelude::PrimaryKey for #name {
+                    type Key = #key_ty;
 
-                        let mut conn = crate::establish_connection();
+                    fn primary_key(&self) -> Option<Self::Key> {
+                        #primary_key_value
+                    }
+                }
 
-                        let #lower_name_ident = #new_name_ident::#from_name(&#lower_name_ident);
+                #[cfg(not(target_arch = "wasm32"))]
+                pub use #lower_name_ident::{#name, #new_name_ident};
+            );
 
-                        diesel::update(#table_name_ident::table.find(id_given))
-                            .set(&#lower_name_ident)
-                            .execute(&mut conn).map_err(|e| "Error update".to_string())
-                    }
+            res
+        }
+        _ => panic!("Orm 'model': The target must be a named struct."),
+    };
 
-                    pub async fn save(&mut self) -> std::result::Result<(), String> {
-                        use crate::schema::#table_name_ident;
+    Ok(res)
+}
 
-                        if self.id == -1 {
-                            let res = self.create().await?;
+// A hidden RPC taking a list of ids, with its server and cached client wrappers
+fn push_query(
+    mut method: syn::ImplItemMethod,
+    guard: Option<proc_macro2::TokenStream>,
+    query_id: u64,
+    model_id: u64,
+    enabled_endpoints: &mut Vec<proc_macro2::TokenStream>,
+    server_wrappers: &mut Vec<proc_macro2::TokenStream>,
+    client_wrappers: &mut Vec<proc_macro2::TokenStream>,
+) {
+    let rpc_name: syn::Ident = syn::parse_str(&format!("__{}", method.sig.ident)).unwrap();
+    let sig = method.sig.clone();
 
-                            self.id = res.id;
+    let args = method
+        .sig
+        .decl
+        .inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            syn::FnArg::Captured(c) => Some(c.pat.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let args = &args;
 
-                        } else {
-                            #name::update(self.id, self.clone()).await?;
+    server_wrappers.push(delegate(&method, &rpc_name));
+    client_wrappers.push(quote! {
+        pub #sig {
+            comet::prelude::cached_query(
+                &crate::CACHE,
+                comet::prelude::Query::new(
+                    #query_id,
+                    vec![#(comet::prelude::model_key(&#args)),*],
+                    #model_id,
+                ),
+                Self::#rpc_name(#(#args),*),
+            )
+            .await
+        }
+    });
+
+    method.sig.ident = rpc_name;
+
+    enabled_endpoints.push(quote! {
+        #guard
+        #[doc(hidden)]
+        #method
+    });
+}
+
+// Server side, the method directly calls its RPC
+fn delegate(method: &syn::ImplItemMethod, target: &syn::Ident) -> proc_macro2::TokenStream {
+    let vis = &method.vis;
+    let sig = &method.sig;
+
+    let mut receiver = None;
+    let mut args = vec![];
+
+    for arg in &method.sig.decl.inputs {
+        match arg {
+            syn::FnArg::SelfRef(_) => receiver = Some(quote! { self }),
+            syn::FnArg::Captured(c) => {
+                let pat = &c.pat;
+
+                args.push(quote! { #pat });
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    match receiver {
+        Some(receiver) => quote! {
+            #vis #sig {
+                #receiver.#target(#(#args),*).await
+            }
+        },
+        None => quote! {
+            #vis #sig {
+                Self::#target(#(#args),*).await
+            }
+        },
+    }
+}
+
+// Client side, the queries hit the cache before the socket, and the mutations update it
+fn cached_endpoint(
+    endpoint: &str,
+    optimistic: bool,
+    is_serial: bool,
+    name: &syn::Ident,
+    lower_name_ident: &syn::Ident,
+    model_id: u64,
+    method: &syn::ImplItemMethod,
+) -> proc_macro2::TokenStream {
+    let sig = &method.sig;
+
+    // The position of the endpoint identifies its query
+    let query_id = ENDPOINTS.iter().position(|e| *e == endpoint).unwrap() as u64;
+
+    // A model with a key of its own is new when the cache doesn't know it
+    let is_new = if is_serial {
+        quote! { self.id.is_none() }
+    } else {
+        quote! {
+            crate::CACHE.with(|cache| {
+                cache
+                    .borrow()
+                    .model(#model_id, comet::prelude::PrimaryKey::model_key(self))
+                    .is_none()
+            })
+        }
+    };
+
+    let body = match endpoint {
+        "create" => quote! {
+            comet::prelude::Validate::validate(self)?;
+
+            let created = self.__create().await?;
+
+            crate::CACHE.with(|cache| cache.borrow_mut().invalidate(#model_id, vec![]));
+
+            Ok(created)
+        },
+        "list" => quote! {
+            comet::prelude::cached_query(
+                &crate::CACHE,
+                comet::prelude::Query::new(#query_id, vec![], #model_id),
+                #name::__list(),
+            )
+            .await
+        },
+        "update" => quote! {
+            comet::prelude::Validate::validate(&#lower_name_ident)?;
+
+            let updated = #name::__update(id_given, #lower_name_ident.clone()).await?;
+
+            crate::CACHE.with(|cache| cache.borrow_mut().update_model(#lower_name_ident.into()));
+
+            Ok(updated)
+        },
+        "save" if optimistic => quote! {
+            comet::prelude::Validate::validate(self)?;
+
+            let is_new = #is_new;
+
+            // A new model has no id yet, it waits for the server
+            let previous = if is_new {
+                None
+            } else {
+                let previous = crate::CACHE.with(|cache| {
+                    let mut cache = cache.borrow_mut();
+
+                    let previous = cache.model(#model_id, comet::prelude::PrimaryKey::model_key(self));
+
+                    cache.update_model(self.clone().into());
+
+                    previous
+                });
+
+                comet::prelude::redraw();
+
+                Some(previous)
+            };
+
+            if let Err(error) = self.__save().await {
+                // The server rejected the change, back to what it knows
+                if let Some(previous) = previous {
+                    crate::CACHE.with(|cache| {
+                        let mut cache = cache.borrow_mut();
+
+                        match &previous {
+                            Some(previous) => cache.update_model(previous.clone()),
+                            None => cache.remove_model(#model_id, comet::prelude::PrimaryKey::model_key(self)),
                         }
+                    });
 
-                        Ok(())
+                    let previous: Option<#name> =
+                        previous.and_then(|previous| std::convert::TryFrom::try_from(previous).ok());
+
+                    if let Some(previous) = previous {
+                        *self = previous;
                     }
 
-                    pub async fn delete(id_given: i32) -> std::result::Result<usize, String> {
-                        use crate::schema::#table_name_ident;
+                    comet::prelude::redraw();
+                }
 
-                        let mut conn = crate::establish_connection();
+                return Err(error);
+            }
 
-                        diesel::delete(#table_name_ident::table.find(id_given))
-                            .execute(&mut conn).map_err(|e| "Error delete".to_string())
-                    }
+            crate::CACHE.with(|cache| {
+                let mut cache = cache.borrow_mut();
 
-                    pub async fn fetch(id_given: i32) -> std::result::Result<#name, String> {
-                        use crate::schema::#table_name_ident;
+                if is_new {
+                    cache.invalidate(#model_id, vec![]);
+                } else {
+                    // Reconcile with what the server saved
+                    cache.update_model(self.clone().into());
+                }
+            });
 
-                        let mut conn = crate::establish_connection();
+            Ok(())
+        },
+        "delete" if optimistic => quote! {
+            let key = comet::prelude::model_key(&id_given);
 
-                        #table_name_ident::table.filter(#table_name_ident::dsl::id.eq(id_given)).first::<#name>(&mut conn).map_err(|e| "Error fetch".to_string())
+            let previous = crate::CACHE.with(|cache| {
+                let mut cache = cache.borrow_mut();
+
+                let previous = cache.model(#model_id, key.clone());
+
+                cache.remove_model(#model_id, key.clone());
+
+                previous
+            });
+
+            comet::prelude::redraw();
+
+            match #name::__delete(id_given).await {
+                Ok(deleted) => {
+                    crate::CACHE.with(|cache| cache.borrow_mut().invalidate(#model_id, vec![key]));
+
+                    Ok(deleted)
+                }
+                Err(error) => {
+                    // The server rejected the deletion, the model comes back
+                    if let Some(previous) = previous {
+                        crate::CACHE.with(|cache| cache.borrow_mut().update_model(previous));
                     }
+
+                    comet::prelude::redraw();
+
+                    Err(error)
                 }
+            }
+        },
+        "save" => quote! {
+            comet::prelude::Validate::validate(self)?;
 
-                #[cfg(not(target_arch = "wasm32"))]
-                pub use #lower_name_ident::{#name, #new_name_ident};
-            );
+            let is_new = #is_new;
 
-            res
+            self.__save().await?;
+
+            crate::CACHE.with(|cache| {
+                let mut cache = cache.borrow_mut();
+
+                // A new model may be part of any list
+                if is_new {
+                    cache.invalidate(#model_id, vec![]);
+                } else {
+                    cache.update_model(self.clone().into());
+                }
+            });
+
+            Ok(())
+        },
+        "delete" => quote! {
+            let key = comet::prelude::model_key(&id_given);
+
+            let deleted = #name::__delete(id_given).await?;
+
+            crate::CACHE.with(|cache| cache.borrow_mut().invalidate(#model_id, vec![key]));
+
+            Ok(deleted)
+        },
+        "fetch" => quote! {
+            comet::prelude::cached_query(
+                &crate::CACHE,
+                comet::prelude::Query::new(#query_id, vec![comet::prelude::model_key(&id_given)], #model_id),
+                async move { #name::__fetch(id_given).await.map(|model| vec![model]) },
+            )
+            .await?
+            .pop()
+            .ok_or(comet::prelude::DbError::NotFound)
+        },
+        _ => unreachable!(),
+    };
+
+    quote! {
+        pub #sig {
+            #body
         }
-        _ => panic!("Orm 'model': The target must be a named struct."),
+    }
+}
+
+// The enum of every model, stored in the client cache
+pub fn generate_model_enum(_input: TokenStream) -> TokenStream {
+    let models = MODELS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|name| syn::parse_str::<syn::Ident>(name).unwrap())
+        .collect::<Vec<_>>();
+
+    let model_ids = (0..models.len() as u64).collect::<Vec<_>>();
+
+    let models2 = models.clone();
+    let models3 = models.clone();
+    let models4 = models.clone();
+    let models5 = models.clone();
+    let models6 = models.clone();
+    let models7 = models.clone();
+    let models8 = models.clone();
+    let models9 = models.clone();
+
+    let tt = quote! {
+        #[derive(Clone)]
+        pub enum Model {
+            #(#models(#models2)),*
+        }
+
+        impl comet::prelude::CacheModel for Model {
+            fn id(&self) -> comet::prelude::ModelKey {
+                match *self {
+                    #(Model::#models3(ref model) => comet::prelude::PrimaryKey::model_key(model)),*
+                }
+            }
+
+            fn model_id(&self) -> comet::prelude::ModelId {
+                match *self {
+                    #(Model::#models4(_) => #model_ids),*
+                }
+            }
+        }
+
+        #(
+            impl From<#models5> for Model {
+                fn from(model: #models6) -> Self {
+                    Model::#models7(model)
+                }
+            }
+
+            impl std::convert::TryFrom<Model> for #models8 {
+                type Error = Model;
+
+                #[allow(unreachable_patterns)]
+                fn try_from(model: Model) -> std::result::Result<Self, Self::Error> {
+                    match model {
+                        Model::#models9(model) => Ok(model),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
     };
 
-    Ok(res)
+    tt.into()
 }
@@ -2,8 +2,13 @@ use lazy_static::lazy_static;
 use proc_macro::TokenStream;
 use std::sync::{Arc, RwLock};
 
-use quote::quote;
-use syn::{parse::Result, parse_macro_input, ImplItem};
+use quote::{quote, quote_spanned};
+use syn::{
+    parse::{Parse, ParseStream, Result},
+    parse_macro_input, spanned::Spanned, ImplItem,
+};
+
+use crate::model_macro::MODELS;
 
 #[derive(Debug)]
 pub struct RpcEntry {
@@ -13,6 +18,77 @@ pub struct RpcEntry {
     query_types: Vec<(bool, String)>, // (is_mut, type)
     response_variant: String,
     response_type: (bool, String), // (is mut self, ret)
+    guards: Vec<(GuardArgs, String)>,
+    // The first argument is `self`
+    takes_self: bool,
+}
+
+// What a guard receives after the request context
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GuardArgs {
+    // The guard of an impl
+    Nothing,
+    // A `#[guard(...)]` method guard gets the arguments of the method.
+    // When `self` is a model, it gets the row stored for its key, never the one sent by the client
+    Stored,
+    // The policy of a generated endpoint gets the arguments as sent, and loads the rows it needs itself
+    Sent,
+}
+
+// The RPCs whose response type is a Result, matched as `Err` to find the failed responses
+fn is_result(ty: &str) -> bool {
+    let ty = ty.replace(' ', "");
+
+    ty.starts_with("Result<")
+        || ty.starts_with("std::result::Result<")
+        || ty.starts_with("::std::result::Result<")
+}
+
+// #[rpc(guard = my_guard, transaction)]
+#[derive(Default)]
+pub struct RpcArgs {
+    pub guard: Option<syn::Expr>,
+    // Every method of the impl runs in a database transaction
+    pub transaction: bool,
+}
+
+impl Parse for RpcArgs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut args = RpcArgs::default();
+
+        while !input.is_empty() {
+            let name: syn::Ident = input.parse()?;
+
+            match name.to_string().as_str() {
+                "guard" => {
+                    input.parse::<syn::Token![=]>()?;
+
+                    args.guard = Some(input.parse()?);
+                }
+                "transaction" => args.transaction = true,
+                _ => return Err(syn::Error::new(name.span(), "Unknown rpc argument")),
+            }
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+// #[guard(my_guard)] on a method
+struct GuardAttr(syn::Expr);
+
+impl Parse for GuardAttr {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+
+        syn::parenthesized!(content in input);
+
+        Ok(GuardAttr(content.parse()?))
+    }
 }
 
 lazy_static! {
@@ -20,24 +96,30 @@ lazy_static! {
     pub static ref RPCS: Arc<RwLock<Vec<RpcEntry>>> = Arc::new(RwLock::new(vec![])); // QueryId, ModelName, FnName, InputEnum, OutputEnum
 }
 
-pub fn perform(input: TokenStream) -> TokenStream {
+pub fn perform(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as RpcArgs);
     let mcall = parse_macro_input!(input as syn::ItemImpl);
 
     proc_macro::TokenStream::from(
-        register_rpcs(mcall).unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
+        register_rpcs(args, mcall).unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
     )
 }
 
-pub fn register_rpcs(mut mcall: syn::ItemImpl) -> Result<proc_macro2::TokenStream> {
+pub fn register_rpcs(args: RpcArgs, mut mcall: syn::ItemImpl) -> Result<proc_macro2::TokenStream> {
     let self_type = *mcall.self_ty.clone();
     let res = mcall
         .items
         .iter()
         .map(|item| match item {
-            ImplItem::Method(method) => register_rpc(self_type.clone(), method).unwrap(),
+            ImplItem::Method(method) => register_rpc(
+                self_type.clone(),
+                method,
+                args.guard.as_ref(),
+                args.transaction,
+            ),
             _ => unimplemented!(),
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
 
     mcall.items = res
         .iter()
@@ -53,8 +135,70 @@ pub fn register_rpcs(mut mcall: syn::ItemImpl) -> Result<proc_macro2::TokenStrea
 pub fn register_rpc(
     self_type: syn::Type,
     mcall: &syn::ImplItemMethod,
+    impl_guard: Option<&syn::Expr>,
+    impl_transaction: bool,
 ) -> Result<Vec<proc_macro2::TokenStream>> {
-    let server_fn = mcall.clone();
+    let mut mcall = mcall.clone();
+
+    // The guard of the impl only gets the context, the ones of the method also get its arguments
+    let mut guards = impl_guard
+        .map(|guard| (GuardArgs::Nothing, quote! { #guard }.to_string()))
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    let mut attrs = vec![];
+
+    // A mutation marked with #[queue] is persisted while offline, and sent once reconnected
+    let mut queued = false;
+
+    // A method marked with #[transaction] commits all its database writes at once, or none of them
+    let mut transaction = impl_transaction;
+
+    for attr in mcall.attrs.drain(..) {
+        if attr.path.is_ident("queue") {
+            queued = true;
+        } else if attr.path.is_ident("transaction") {
+            transaction = true;
+        } else if attr.path.is_ident("guard") {
+            let GuardAttr(guard) = syn::parse2(attr.tts.clone())?;
+
+            guards.push((GuardArgs::Stored, quote! { #guard }.to_string()));
+        } else if attr.path.is_ident("endpoint_guard") {
+            // Set by `#[model]` on its endpoints
+            let GuardAttr(guard) = syn::parse2(attr.tts.clone())?;
+
+            guards.push((GuardArgs::Sent, quote! { #guard }.to_string()));
+        } else {
+            attrs.push(attr);
+        }
+    }
+
+    mcall.attrs = attrs;
+
+    let mut server_fn = mcall.clone();
+
+    if transaction {
+        let output = match &server_fn.sig.decl.output {
+            syn::ReturnType::Type(_, ty) if server_fn.sig.asyncness.is_some() => ty.clone(),
+            _ => {
+                return Err(syn::Error::new(
+                    server_fn.sig.ident.span(),
+                    "A transaction method must be async and return a Result",
+                ))
+            }
+        };
+
+        let block = &server_fn.block;
+
+        // Any alias of a `Result` whose error is `From<DbError>` will do,
+        // the other types fail on the return type of the method
+        let body = quote_spanned! {output.span()=>
+            comet::prelude::transaction(|| async move #block).await
+        };
+
+        server_fn.block = syn::parse_quote! { { #body } };
+    }
+
     let mut client_fn = mcall.clone();
 
     let query_types = mcall
@@ -78,6 +222,11 @@ pub fn register_rpc(
         })
         .collect::<Vec<_>>();
 
+    let takes_self = match mcall.sig.decl.inputs.first() {
+        Some(arg) => matches!(arg.value(), syn::FnArg::SelfRef(_)),
+        None => false,
+    };
+
     let rpc_nb = RPCS.read().unwrap().len() as u64;
 
     let query_variant = format!("RPCQuery{}", rpc_nb);
@@ -93,7 +242,7 @@ pub fn register_rpc(
     };
 
     let response_type = quote! { #response_type }.to_string();
-    let response_type = if let Some((true, _)) = query_types.get(0) {
+    let response_type = if let Some((true, _)) = query_types.first() {
         (true, response_type)
     } else {
         (false, response_type)
@@ -116,6 +265,8 @@ pub fn register_rpc(
         query_types,
         response_variant,
         response_type,
+        guards,
+        takes_self,
     });
 
     let query_args = mcall
@@ -134,16 +285,23 @@ pub fn register_rpc(
         })
         .collect::<Vec<_>>();
 
+    let send: syn::Ident = if queued {
+        syn::parse_quote! { rpc_queued }
+    } else {
+        syn::parse_quote! { rpc }
+    };
+
     let client_wrap: syn::Block = syn::parse_quote! {
         {
-            let response = if let Some(socket) = crate::SOCKET.write().await.as_mut() {
-                socket.rpc(Proto::RPCQuery(RPCQuery::#query_variant_real(#(#query_args.clone()),*))).await
+            let response = if let Some(socket) = crate::SOCKET.read().await.as_ref() {
+                socket.#send(Proto::RPCQuery(RPCQuery::#query_variant_real(#(#query_args.clone()),*))).await
             } else {
                     panic!("No socket")
             };
 
             let response = match response {
-                Proto::RPCResponse(RPCResponse::#response_variant_real(#(#response_self,)* response)) => { #(*self = #response_self2;)* response},
+                Ok(Proto::RPCResponse(RPCResponse::#response_variant_real(#(#response_self,)* response))) => { #(*self = #response_self2;)* response},
+                Err(e) => panic!("Malformed response from the server: {}", e),
                 _ => unimplemented!(),
             };
 
@@ -185,7 +343,7 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
                                 .query_types
                                 .iter()
                                 .map(|(is_mut, s)| {
-                                    (*is_mut, syn::parse_str::<syn::Type>(&s).unwrap())
+                                    (*is_mut, syn::parse_str::<syn::Type>(s).unwrap())
                                 })
                                 .collect::<Vec<_>>(),
                             rpc_entry
@@ -194,11 +352,7 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
                                 .enumerate()
                                 .map(|(id, (is_mut, _s))| {
                                     let id = syn::Ident::new(
-                                        &if *is_mut {
-                                            format!("arg_{}", id)
-                                        } else {
-                                            format!("arg_{}", id)
-                                        },
+                                        &format!("arg_{}", id),
                                         proc_macro2::Span::call_site(),
                                     );
                                     (*is_mut, syn::parse_quote! { #id })
@@ -301,6 +455,134 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
         .collect::<Vec<Vec<syn::Ident>>>();
     let response_self2 = response_self.clone();
 
+    // The guards are evaluated server side only, before the call. A refusal is sent back as an error
+    let guard_checks = RPCS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|rpc_entry| {
+            let response_variant: syn::Ident =
+                syn::parse_str(&rpc_entry.response_variant).unwrap();
+
+            let args = (0..rpc_entry.query_types.len())
+                .map(|id| syn::Ident::new(&format!("arg_{}", id), proc_macro2::Span::call_site()))
+                .collect::<Vec<_>>();
+
+            let response_self: Vec<syn::Ident> = if rpc_entry.response_type.0 {
+                vec![syn::parse_quote! { arg_0 }]
+            } else {
+                vec![]
+            };
+
+            let model: syn::Type = syn::parse_str(&rpc_entry.model_name).unwrap();
+
+            // A method guard judges the model as it is in the database, the client can send anything
+            let uses_stored = rpc_entry.takes_self
+                && MODELS.read().unwrap().contains(&rpc_entry.model_name)
+                && rpc_entry.guards.iter().any(|(guard_args, _)| *guard_args == GuardArgs::Stored);
+
+            let forbidden = quote! {
+                return Some(Proto::RPCResponse(RPCResponse::#response_variant(
+                    #(#response_self,)* Err(comet::prelude::Forbidden.into())
+                )));
+            };
+
+            let checks = rpc_entry
+                .guards
+                .iter()
+                .map(|(guard_args, guard)| {
+                    let guard: syn::Expr = syn::parse_str(guard).unwrap();
+
+                    let allowed = match guard_args {
+                        GuardArgs::Nothing => quote! { (#guard)(&context).await },
+                        GuardArgs::Stored if uses_stored => {
+                            let other_args = &args[1..];
+
+                            quote! { (#guard)(&context, &stored, #(&#other_args),*).await }
+                        }
+                        GuardArgs::Stored | GuardArgs::Sent => {
+                            let args = &args;
+
+                            quote! { (#guard)(&context, #(&#args),*).await }
+                        }
+                    };
+
+                    quote! {
+                        if !#allowed {
+                            #forbidden
+                        }
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            if checks.is_empty() {
+                quote! {}
+            } else if uses_stored {
+                // The method runs on the stored row it was allowed for, whatever the client changed in its copy
+                let run_on_stored = if rpc_entry.query_types[0].0 {
+                    quote! { { arg_0 = stored; } }
+                } else {
+                    quote! { let arg_0 = stored; }
+                };
+
+                quote! {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    let stored = {
+                        let context = comet::prelude::request_context();
+
+                        let stored = match comet::prelude::PrimaryKey::primary_key(&arg_0) {
+                            Some(key) => <#model>::fetch(key).await.ok(),
+                            None => None,
+                        };
+
+                        // An unsaved or deleted model has no stored row to judge, it is refused
+                        let stored = match stored {
+                            Some(stored) => stored,
+                            None => { #forbidden }
+                        };
+
+                        #(#checks)*
+
+                        stored
+                    };
+
+                    #[cfg(not(target_arch = "wasm32"))]
+                    #run_on_stored
+                }
+            } else {
+                quote! {
+                    #[cfg(not(target_arch = "wasm32"))]
+                    {
+                        let context = comet::prelude::request_context();
+
+                        #(#checks)*
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let error_arms = RPCS
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|rpc_entry| is_result(&rpc_entry.response_type.1))
+        .map(|rpc_entry| {
+            let response_variant: syn::Ident =
+                syn::parse_str(&rpc_entry.response_variant).unwrap();
+
+            let response_self = if rpc_entry.response_type.0 {
+                vec![quote! { _ }]
+            } else {
+                vec![]
+            };
+
+            quote! {
+                RPCResponse::#response_variant(#(#response_self,)* Err(e)) => Some(format!("{:?}", e)),
+            }
+        })
+        .collect::<Vec<_>>();
+
     let proto = quote! {
         #[derive(Serialize, Deserialize, Debug, Clone)]
         #[serde(crate = "comet::prelude::serde")] // must be below the derive attribute
@@ -316,13 +598,16 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
         impl RPCQuery {
         }
 
-        #[async_trait]
+        #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+        #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
         impl comet::prelude::ProtoTrait for RPCQuery {
             type Response = Proto;
 
             async fn dispatch(self) -> Option<Self::Response> {
                 match self {
                     #(RPCQuery::#query_variants2(#(#query_params),*) => {
+                        #guard_checks
+
                         let res = #models::#methods(#(#query_params_with_ref),*).await;
                         Some(Proto::RPCResponse(RPCResponse::#response_variants2(#(#response_self,)* res)))
                     }),*
@@ -334,7 +619,8 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
         impl RPCResponse {
         }
 
-        #[async_trait]
+        #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+        #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
         impl comet::prelude::ProtoTrait for RPCResponse {
             type Response = Proto;
 
@@ -346,6 +632,13 @@ pub fn generate_rpc_proto(_input: TokenStream) -> TokenStream {
                     _ => todo!(),
                 }
             }
+
+            fn error(&self) -> Option<String> {
+                match self {
+                    #(#error_arms)*
+                    _ => None,
+                }
+            }
         }
 
     };
@@ -0,0 +1,966 @@
+// Shared with the `comet` binary, that generates the migrations
+#![allow(dead_code)]
+
+use syn::{
+    parse::{Parse, ParseStream, Parser, Result},
+    Fields, ItemEnum, ItemStruct,
+};
+
+// The schema the last migration was generated from
+pub const SNAPSHOT: &str = "migrations/.comet_schema";
+
+pub const ENDPOINTS: [&str; 6] = ["create", "list", "update", "save", "delete", "fetch"];
+
+// The models generated by `comet::auth!`, read by the binary for their migrations.
+// None of their endpoints are exposed, the password hashes and the tokens never leave the server
+pub const AUTH_MODELS: &str = "
+    #[model(disable(create, list, update, delete, fetch))]
+    #[derive(Default, Debug)]
+    pub struct User {
+        #[unique]
+        pub username: String,
+        pub password_hash: String,
+    }
+
+    #[model(disable(create, list, update, delete, fetch))]
+    #[derive(Default, Debug)]
+    pub struct Session {
+        #[unique]
+        pub token: String,
+        pub user_id: i32,
+        pub expires_at: chrono::NaiveDateTime,
+    }
+";
+
+// #[model], #[model("table_name")] or
+// #[model(table = "table_name", policy = MyPolicy, disable(delete), optimistic, primary_key = i64,
+//         timestamps, soft_delete)]
+#[derive(Default)]
+pub struct ModelArgs {
+    pub table_name: Option<String>,
+    pub policy: Option<syn::Type>,
+    pub disabled: Vec<String>,
+    // save() and delete() update the client cache before the server answers
+    pub optimistic: bool,
+    // The type of the `id`, i32 by default
+    pub primary_key: Option<syn::Type>,
+    // `created_at` and `updated_at` columns, set by the server
+    pub timestamps: bool,
+    // delete() sets a `deleted_at` column, the deleted rows are never loaded
+    pub soft_delete: bool,
+}
+
+impl Parse for ModelArgs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut args = ModelArgs::default();
+
+        while !input.is_empty() {
+            if input.peek(syn::LitStr) {
+                args.table_name = Some(input.parse::<syn::LitStr>()?.value());
+            } else {
+                let name: syn::Ident = input.parse()?;
+
+                match name.to_string().as_str() {
+                    "table" => {
+                        input.parse::<syn::Token![=]>()?;
+
+                        args.table_name = Some(input.parse::<syn::LitStr>()?.value());
+                    }
+                    "policy" => {
+                        input.parse::<syn::Token![=]>()?;
+
+                        args.policy = Some(input.parse()?);
+                    }
+                    "optimistic" => args.optimistic = true,
+                    "timestamps" => args.timestamps = true,
+                    "soft_delete" => args.soft_delete = true,
+                    "primary_key" => {
+                        input.parse::<syn::Token![=]>()?;
+
+                        let ty = input.parse()?;
+
+                        id_type(Some(&ty))?;
+
+                        args.primary_key = Some(ty);
+                    }
+                    "disable" => {
+                        let content;
+
+                        syn::parenthesized!(content in input);
+
+                        let endpoints = content
+                            .parse_terminated::<syn::Ident, syn::Token![,]>(syn::Ident::parse)?;
+
+                        for endpoint in endpoints {
+                            if !ENDPOINTS.contains(&endpoint.to_string().as_str()) {
+                                return Err(syn::Error::new(
+                                    endpoint.span(),
+                                    "Unknown endpoint, expected one of create, list, update, save, delete, fetch",
+                                ));
+                            }
+
+                            args.disabled.push(endpoint.to_string());
+                        }
+                    }
+                    _ => return Err(syn::Error::new(name.span(), "Unknown model argument")),
+                }
+            }
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        // save() creates or updates, so it goes away with any of them
+        if args.disabled.iter().any(|e| e == "create" || e == "update") {
+            args.disabled.push("save".to_string());
+        }
+
+        Ok(args)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Column {
+    pub name: String,
+    pub ty: String,
+    // From an `Option<T>` field
+    pub nullable: bool,
+    // The allowed values of a `#[db_enum]` column
+    pub variants: Vec<String>,
+    // The table referenced by a `#[belongs_to]` foreign key
+    pub references: Option<String>,
+    pub unique: bool,
+    pub index: bool,
+    // The SQL value of a `#[default(...)]`
+    pub default: Option<String>,
+    // Part of the natural key of the table
+    pub primary_key: bool,
+    // The previous name of the column, from a `#[renamed_from("name")]`
+    pub renamed_from: Option<String>,
+    // The model referenced by a `#[belongs_to]` foreign key, see `resolve_foreign_keys`
+    pub parent: Option<String>,
+}
+
+// `renamed_from` only tells the next migration what to do and `parent` is resolved
+// into `references`, they aren't part of the schema
+impl PartialEq for Column {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.ty == other.ty
+            && self.nullable == other.nullable
+            && self.variants == other.variants
+            && self.references == other.references
+            && self.unique == other.unique
+            && self.index == other.index
+            && self.default == other.default
+            && self.primary_key == other.primary_key
+    }
+}
+
+// #[unique], #[index], #[default(value)], #[column("name")], #[skip], #[max_len(n)],
+// #[primary_key] and #[renamed_from("name")] on a field
+#[derive(Clone, Debug, Default)]
+pub struct FieldAttrs {
+    pub unique: bool,
+    pub index: bool,
+    pub default: Option<String>,
+    pub column: Option<String>,
+    // Only on the client, not stored
+    pub skip: bool,
+    pub max_len: Option<u32>,
+    pub primary_key: bool,
+    pub renamed_from: Option<String>,
+}
+
+const FIELD_ATTRS: [&str; 8] = [
+    "unique",
+    "index",
+    "default",
+    "column",
+    "skip",
+    "max_len",
+    "primary_key",
+    "renamed_from",
+];
+
+impl FieldAttrs {
+    pub fn is_field_attr(attr: &syn::Attribute) -> bool {
+        FIELD_ATTRS.iter().any(|name| attr.path.is_ident(name))
+    }
+
+    pub fn parse(field: &syn::Field) -> Result<Self> {
+        let mut attrs = FieldAttrs::default();
+
+        for attr in field.attrs.iter().filter(|attr| Self::is_field_attr(attr)) {
+            let name = attr.path.segments[0].ident.to_string();
+
+            match name.as_str() {
+                "unique" => attrs.unique = true,
+                "index" => attrs.index = true,
+                "skip" => attrs.skip = true,
+                "primary_key" => attrs.primary_key = true,
+                "default" => attrs.default = Some(sql_literal(&attr_arg(attr)?)?),
+                "column" => match attr_arg(attr)? {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(name),
+                        ..
+                    }) => attrs.column = Some(name.value()),
+                    other => return Err(syn::Error::new_spanned(other, "Expected a column name")),
+                },
+                "renamed_from" => match attr_arg(attr)? {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Str(name),
+                        ..
+                    }) => attrs.renamed_from = Some(name.value()),
+                    other => return Err(syn::Error::new_spanned(other, "Expected a column name")),
+                },
+                "max_len" => match attr_arg(attr)? {
+                    syn::Expr::Lit(syn::ExprLit {
+                        lit: syn::Lit::Int(len),
+                        ..
+                    }) => attrs.max_len = Some(len.value() as u32),
+                    other => return Err(syn::Error::new_spanned(other, "Expected a length")),
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        Ok(attrs)
+    }
+}
+
+// The value inside `#[attr(value)]`
+fn attr_arg(attr: &syn::Attribute) -> Result<syn::Expr> {
+    let parser = |input: ParseStream| {
+        let content;
+
+        syn::parenthesized!(content in input);
+
+        content.parse::<syn::Expr>()
+    };
+
+    parser.parse2(attr.tts.clone())
+}
+
+// A Rust literal as an SQL value
+fn sql_literal(expr: &syn::Expr) -> Result<String> {
+    match expr {
+        syn::Expr::Lit(lit) => match &lit.lit {
+            syn::Lit::Str(s) => Ok(format!("'{}'", s.value().replace('\'', "''"))),
+            syn::Lit::Int(i) => Ok(i.value().to_string()),
+            syn::Lit::Float(f) => Ok(f.value().to_string()),
+            syn::Lit::Bool(b) => Ok(b.value.to_string()),
+            other => Err(syn::Error::new_spanned(other, "Unsupported default value")),
+        },
+        syn::Expr::Unary(syn::ExprUnary {
+            op: syn::UnOp::Neg(_),
+            expr,
+            ..
+        }) => Ok(format!("-{}", sql_literal(expr)?)),
+        other => Err(syn::Error::new_spanned(other, "Unsupported default value")),
+    }
+}
+
+// The column storing the field
+pub fn column_name(field: &syn::Field) -> String {
+    FieldAttrs::parse(field)
+        .ok()
+        .and_then(|attrs| attrs.column)
+        .unwrap_or_else(|| field.ident.as_ref().unwrap().to_string())
+}
+
+#[derive(Clone, Debug)]
+pub struct Table {
+    pub name: String,
+    // The SQL type of the `id` column, None when the key is made of `#[primary_key]` columns
+    pub id: Option<String>,
+    pub columns: Vec<Column>,
+    // The name of the model stored in the table, empty when read from the snapshot
+    pub model: String,
+}
+
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && self.id == other.id && self.columns == other.columns
+    }
+}
+
+impl Table {
+    // The columns of the primary key, in order
+    pub fn primary_key(&self) -> Vec<String> {
+        match &self.id {
+            Some(_) => vec!["id".to_string()],
+            None => self
+                .columns
+                .iter()
+                .filter(|column| column.primary_key)
+                .map(|column| column.name.clone())
+                .collect(),
+        }
+    }
+
+    // The SQL type of the foreign keys to the table, None for a key made of many columns
+    pub fn foreign_key_type(&self) -> Option<String> {
+        match self.id.as_deref() {
+            Some("SERIAL") => Some("INTEGER".to_string()),
+            Some("BIGSERIAL") => Some("BIGINT".to_string()),
+            Some(id) => Some(id.to_string()),
+            None => {
+                let key = self.columns.iter().filter(|column| column.primary_key).collect::<Vec<_>>();
+
+                match key[..] {
+                    [column] => Some(column.ty.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+// The SQL type of the `id` of `#[model(primary_key = ...)]`
+pub fn id_type(primary_key: Option<&syn::Type>) -> Result<&'static str> {
+    let primary_key = match primary_key {
+        Some(primary_key) => primary_key,
+        None => return Ok("SERIAL"),
+    };
+
+    let name = match primary_key {
+        syn::Type::Path(path) => path.path.segments.iter().last().map(|s| s.ident.to_string()),
+        _ => None,
+    };
+
+    match name.as_deref() {
+        Some("i32") => Ok("SERIAL"),
+        Some("i64") => Ok("BIGSERIAL"),
+        Some("Uuid") => Ok("UUID"),
+        _ => Err(syn::Error::new_spanned(
+            primary_key,
+            "Expected i32, i64 or Uuid, or a #[primary_key] field",
+        )),
+    }
+}
+
+// The arguments of a `#[model(...)]` attribute found in a source file
+pub fn model_args(attr: &syn::Attribute) -> Result<ModelArgs> {
+    if attr.tts.is_empty() {
+        return Ok(ModelArgs::default());
+    }
+
+    let parser = |input: ParseStream| {
+        let content;
+
+        syn::parenthesized!(content in input);
+
+        content.parse::<ModelArgs>()
+    };
+
+    parser.parse2(attr.tts.clone())
+}
+
+// The types declared with `#[json]` and `#[db_enum]`, that can be used in the models
+#[derive(Clone, Debug, Default)]
+pub struct CustomTypes {
+    pub json: Vec<String>,
+    // The name of the enum and its variants
+    pub enums: Vec<(String, Vec<String>)>,
+}
+
+pub fn enum_variants(item: &ItemEnum) -> Result<Vec<String>> {
+    item.variants
+        .iter()
+        .map(|variant| match variant.fields {
+            Fields::Unit => Ok(variant.ident.to_string()),
+            _ => Err(syn::Error::new(
+                variant.ident.span(),
+                "Only unit variants can be stored in the database",
+            )),
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelationKind {
+    BelongsTo,
+    HasMany,
+}
+
+// #[belongs_to(User)], #[has_many(Todo)] or #[belongs_to(User, foreign_key = "owner_id")]
+#[derive(Clone, Debug)]
+pub struct Relation {
+    pub kind: RelationKind,
+    pub model: syn::Ident,
+    // The column of the model that belongs to the other
+    pub foreign_key: String,
+}
+
+impl Relation {
+    pub fn is_relation(attr: &syn::Attribute) -> bool {
+        attr.path.is_ident("belongs_to") || attr.path.is_ident("has_many")
+    }
+
+    // The lowercase name of the related model
+    pub fn lower_model(&self) -> String {
+        self.model.to_string().to_ascii_lowercase()
+    }
+}
+
+// The relations declared on the model named `name`
+pub fn relations(name: &syn::Ident, attrs: &[syn::Attribute]) -> Result<Vec<Relation>> {
+    attrs
+        .iter()
+        .filter(|attr| Relation::is_relation(attr))
+        .map(|attr| {
+            let kind = if attr.path.is_ident("belongs_to") {
+                RelationKind::BelongsTo
+            } else {
+                RelationKind::HasMany
+            };
+
+            let parser = |input: ParseStream| {
+                let content;
+
+                syn::parenthesized!(content in input);
+
+                let model: syn::Ident = content.parse()?;
+                let mut foreign_key = None;
+
+                if content.peek(syn::Token![,]) {
+                    content.parse::<syn::Token![,]>()?;
+
+                    let arg: syn::Ident = content.parse()?;
+
+                    if arg != "foreign_key" {
+                        return Err(syn::Error::new(arg.span(), "Expected foreign_key"));
+                    }
+
+                    content.parse::<syn::Token![=]>()?;
+
+                    foreign_key = Some(content.parse::<syn::LitStr>()?.value());
+                }
+
+                let owner = match kind {
+                    RelationKind::BelongsTo => &model,
+                    RelationKind::HasMany => name,
+                };
+
+                Ok(Relation {
+                    kind: kind.clone(),
+                    foreign_key: foreign_key
+                        .unwrap_or_else(|| owner.to_string().to_ascii_lowercase() + "_id"),
+                    model,
+                })
+            };
+
+            parser.parse2(attr.tts.clone())
+        })
+        .collect()
+}
+
+pub fn struct_to_table(obj: &ItemStruct, args: &ModelArgs, custom: &CustomTypes) -> Table {
+    let fields = match &obj.fields {
+        Fields::Named(fields) => &fields.named,
+        _ => panic!("Only named fields are supported"),
+    };
+
+    // With the name of their field
+    let mut columns = fields
+        .iter()
+        .filter_map(|field| {
+            let attrs = FieldAttrs::parse(field).unwrap_or_default();
+
+            if attrs.skip {
+                return None;
+            }
+
+            let (ty, nullable) = match generic_inner(&field.ty, "Option") {
+                Some(inner) => (inner, true),
+                None => (field.ty.clone(), false),
+            };
+
+            let (mut ty, variants) = rust_to_diesel_type(ty, custom);
+
+            if let (Some(len), "TEXT") = (attrs.max_len, ty.as_str()) {
+                ty = format!("VARCHAR({})", len);
+            }
+
+            let field_name = field.ident.as_ref().unwrap().to_string();
+
+            Some((
+                field_name.clone(),
+                Column {
+                    name: attrs.column.unwrap_or(field_name),
+                    ty,
+                    nullable,
+                    variants,
+                    unique: attrs.unique,
+                    index: attrs.index,
+                    default: attrs.default,
+                    primary_key: attrs.primary_key,
+                    renamed_from: attrs.renamed_from,
+                    ..Default::default()
+                },
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    // The foreign keys are added when not declared
+    for relation in relations(&obj.ident, &obj.attrs).unwrap_or_default() {
+        if relation.kind != RelationKind::BelongsTo {
+            continue;
+        }
+
+        // Until `resolve_foreign_keys` finds the table of the parent
+        let references = Some(relation.lower_model() + "s");
+        let parent = Some(relation.model.to_string());
+
+        match columns.iter_mut().find(|(field, _)| *field == relation.foreign_key) {
+            Some((_, column)) => {
+                column.references = references;
+                column.parent = parent;
+            }
+            // Typed like the key of the parent by `resolve_foreign_keys`
+            None => columns.push((
+                relation.foreign_key.clone(),
+                Column {
+                    name: relation.foreign_key.clone(),
+                    references,
+                    parent,
+                    ..Default::default()
+                },
+            )),
+        }
+    }
+
+    let mut columns = columns.into_iter().map(|(_, column)| column).collect::<Vec<_>>();
+
+    // Added after every field, in the order of `timestamp_fields`
+    for (name, nullable) in timestamp_fields(args) {
+        columns.push(Column {
+            name: name.to_string(),
+            ty: "TIMESTAMP".to_string(),
+            nullable,
+            default: if nullable { None } else { Some("CURRENT_TIMESTAMP".to_string()) },
+            ..Default::default()
+        });
+    }
+
+    let id = if columns.iter().any(|column| column.primary_key) {
+        None
+    } else {
+        Some(id_type(args.primary_key.as_ref()).unwrap_or("SERIAL").to_string())
+    };
+
+    Table {
+        name: args
+            .table_name
+            .clone()
+            .unwrap_or_else(|| obj.ident.to_string().to_ascii_lowercase() + "s"),
+        id,
+        columns,
+        model: obj.ident.to_string(),
+    }
+}
+
+// The foreign keys of `#[belongs_to]` reference the table of their parent, and the ones
+// added to the model take the type of its key. Only known once every model is read,
+// the added ones fail without their parent or when its key is made of many columns
+pub fn resolve_foreign_keys(tables: &mut [Table]) -> std::result::Result<(), String> {
+    let parents = tables
+        .iter()
+        .map(|table| (table.model.clone(), (table.name.clone(), table.foreign_key_type())))
+        .collect::<Vec<_>>();
+
+    for table in tables.iter_mut() {
+        for column in &mut table.columns {
+            let parent = match &column.parent {
+                Some(parent) => parent,
+                None => continue,
+            };
+
+            let found = parents.iter().find(|(model, _)| model == parent).map(|(_, found)| found);
+
+            if let Some((name, _)) = found {
+                column.references = Some(name.clone());
+            }
+
+            if !column.ty.is_empty() {
+                continue;
+            }
+
+            match found {
+                Some((_, Some(ty))) => column.ty = ty.clone(),
+                _ => {
+                    return Err(format!(
+                        "Cannot type the foreign key {}.{} from the key of {}, declare the field",
+                        table.model, column.name, parent
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The columns maintained by the server, and whether they are nullable
+pub fn timestamp_fields(args: &ModelArgs) -> Vec<(&'static str, bool)> {
+    let mut fields = vec![];
+
+    if args.timestamps {
+        fields.push(("created_at", false));
+        fields.push(("updated_at", false));
+    }
+
+    if args.soft_delete {
+        fields.push(("deleted_at", true));
+    }
+
+    fields
+}
+
+// The `T` of a `wrapper<T>`, like `Option<T>` or `Vec<T>`
+pub fn generic_inner(ty: &syn::Type, wrapper: &str) -> Option<syn::Type> {
+    let segment = match ty {
+        syn::Type::Path(path) => path.path.segments.iter().last()?,
+        _ => return None,
+    };
+
+    if segment.ident != wrapper {
+        return None;
+    }
+
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => match args.args.iter().next()? {
+            syn::GenericArgument::Type(inner) => Some(inner.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Whether a field can be filtered and sorted on, JSON and binary columns can't
+pub fn is_comparable(ty: &syn::Type, custom: &CustomTypes) -> bool {
+    let ty = generic_inner(ty, "Option").unwrap_or_else(|| ty.clone());
+
+    // `<Parent as PrimaryKey>::Key`, the type of a foreign key added to the model
+    if let syn::Type::Path(syn::TypePath { qself: Some(_), .. }) = ty {
+        return true;
+    }
+
+    let (sql_ty, _) = rust_to_diesel_type(ty, custom);
+
+    !sql_ty.ends_with("[]") && sql_ty != "JSONB" && sql_ty != "BYTEA"
+}
+
+// The SQL type, and the variants of an enum
+fn rust_to_diesel_type(rust_ty: syn::Type, custom: &CustomTypes) -> (String, Vec<String>) {
+    if let Some(inner) = generic_inner(&rust_ty, "Vec") {
+        // Stored as binary, not as an array
+        if let syn::Type::Path(path) = &inner {
+            if path.path.is_ident("u8") {
+                return ("BYTEA".to_string(), vec![]);
+            }
+        }
+
+        let (inner, variants) = rust_to_diesel_type(inner, custom);
+
+        return (inner + "[]", variants);
+    }
+
+    match rust_ty {
+        syn::Type::Path(path) => {
+            let path = path.path;
+            let path = path.segments;
+            let path = path
+                .iter()
+                .map(|seg| seg.ident.to_string())
+                .collect::<Vec<String>>();
+            let name = path.last().cloned().unwrap_or_default();
+            let path = path.join("::");
+
+            if custom.json.contains(&name) {
+                return ("JSONB".to_string(), vec![]);
+            }
+
+            if let Some((_, variants)) = custom.enums.iter().find(|(e, _)| *e == name) {
+                return ("TEXT".to_string(), variants.clone());
+            }
+
+            let ty = match path.as_str() {
+                "i16" => "SMALLINT",
+                "i32" => "INTEGER",
+                "i64" => "BIGINT",
+                "String" => "TEXT",
+                "bool" => "BOOLEAN",
+                "f32" => "REAL",
+                "f64" => "DOUBLE PRECISION",
+                "chrono::NaiveDateTime" | "NaiveDateTime" => "TIMESTAMP",
+                "chrono::DateTime" | "DateTime" => "TIMESTAMPTZ",
+                "chrono::NaiveDate" | "NaiveDate" => "DATE",
+                "uuid::Uuid" | "Uuid" => "UUID",
+                "serde_json::Value" => "JSONB",
+                "rust_decimal::Decimal" | "Decimal" => "NUMERIC",
+                _ => panic!(
+                    "Unsupported type: {}. Custom types must be declared with #[json] or #[db_enum] before the model",
+                    path
+                ),
+            };
+
+            (ty.to_string(), vec![])
+        }
+        _ => panic!("Unsupported type"),
+    }
+}
+
+// The type of the column in the diesel `table!` definitions
+pub fn diesel_type(sql_ty: &str) -> String {
+    if let Some(inner) = sql_ty.strip_suffix("[]") {
+        return format!("Array<{}>", diesel_type(inner));
+    }
+
+    if sql_ty.starts_with("VARCHAR(") {
+        return "Varchar".to_string();
+    }
+
+    match sql_ty {
+        "SMALLINT" => "Int2",
+        "INTEGER" | "SERIAL" => "Int4",
+        "BIGINT" | "BIGSERIAL" => "Int8",
+        "TEXT" => "Text",
+        "BOOLEAN" => "Bool",
+        "REAL" => "Float4",
+        "DOUBLE PRECISION" => "Float8",
+        "TIMESTAMP" => "Timestamp",
+        "TIMESTAMPTZ" => "Timestamptz",
+        "DATE" => "Date",
+        "UUID" => "Uuid",
+        "JSONB" => "Jsonb",
+        "NUMERIC" => "Numeric",
+        "BYTEA" => "Binary",
+        _ => panic!("Unsupported column type: {}", sql_ty),
+    }
+    .to_string()
+}
+
+// The type of a column in SQLite, that takes the other type names as they are.
+// None for the types it can't store
+pub fn sqlite_type(sql_ty: &str) -> Option<&str> {
+    if sql_ty.ends_with("[]") {
+        return None;
+    }
+
+    match sql_ty {
+        "SERIAL" | "BIGSERIAL" => Some("INTEGER"),
+        "BYTEA" => Some("BLOB"),
+        "UUID" | "JSONB" | "NUMERIC" | "TIMESTAMPTZ" => None,
+        ty => Some(ty),
+    }
+}
+
+// The order in which the models are declared doesn't matter
+pub fn same_tables(a: &[Table], b: &[Table]) -> bool {
+    a.len() == b.len() && a.iter().all(|table| b.contains(table))
+}
+
+// One `table <name>` line per table, followed by an `id <type>` line when it isn't a SERIAL
+// (`id NONE` for a natural key) and a `column <name> <type>` line per column,
+// ending with ` NULL` for the nullable ones, a `variants <variant>...` line for the enums
+// and a `references <table>`, `unique`, `index`, `primary_key` or `default <value>` line
+// for the other properties
+pub fn from_snapshot(content: &str) -> Vec<Table> {
+    let mut tables: Vec<Table> = vec![];
+
+    for line in content.lines() {
+        let mut words = line.splitn(3, ' ');
+
+        match (words.next(), words.next(), words.next()) {
+            (Some("table"), Some(name), None) => tables.push(Table {
+                name: name.to_string(),
+                id: Some("SERIAL".to_string()),
+                columns: vec![],
+                model: String::new(),
+            }),
+            (Some("id"), Some(ty), _) => {
+                if let Some(table) = tables.last_mut() {
+                    table.id = match ty {
+                        "NONE" => None,
+                        _ => Some(line["id ".len()..].to_string()),
+                    };
+                }
+            }
+            (Some("column"), Some(name), Some(ty)) => {
+                let (ty, nullable) = match ty.strip_suffix(" NULL") {
+                    Some(ty) => (ty, true),
+                    None => (ty, false),
+                };
+
+                if let Some(table) = tables.last_mut() {
+                    table.columns.push(Column {
+                        name: name.to_string(),
+                        ty: ty.to_string(),
+                        nullable,
+                        ..Default::default()
+                    });
+                }
+            }
+            (Some("variants"), _, _) => {
+                if let Some(column) = tables.last_mut().and_then(|t| t.columns.last_mut()) {
+                    column.variants = line.split(' ').skip(1).map(String::from).collect();
+                }
+            }
+            (Some(property), value, _) => {
+                let column = match tables.last_mut().and_then(|t| t.columns.last_mut()) {
+                    Some(column) => column,
+                    None => continue,
+                };
+
+                match (property, value) {
+                    ("references", Some(table)) => column.references = Some(table.to_string()),
+                    ("unique", None) => column.unique = true,
+                    ("index", None) => column.index = true,
+                    ("primary_key", None) => column.primary_key = true,
+                    ("default", Some(_)) => {
+                        column.default = Some(line["default ".len()..].to_string())
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    tables
+}
+
+pub fn to_snapshot(tables: &[Table]) -> String {
+    let mut content = String::new();
+
+    for table in tables {
+        content += &format!("table {}\n", table.name);
+
+        match table.id.as_deref() {
+            Some("SERIAL") => (),
+            Some(ty) => content += &format!("id {}\n", ty),
+            None => content += "id NONE\n",
+        }
+
+        for column in &table.columns {
+            content += &format!(
+                "column {} {}{}\n",
+                column.name,
+                column.ty,
+                if column.nullable { " NULL" } else { "" }
+            );
+
+            if !column.variants.is_empty() {
+                content += &format!("variants {}\n", column.variants.join(" "));
+            }
+
+            if let Some(references) = &column.references {
+                content += &format!("references {}\n", references);
+            }
+
+            if column.unique {
+                content += "unique\n";
+            }
+
+            if column.index {
+                content += "index\n";
+            }
+
+            if column.primary_key {
+                content += "primary_key\n";
+            }
+
+            if let Some(default) = &column.default {
+                content += &format!("default {}\n", default);
+            }
+        }
+    }
+
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(model: &str, args: &str) -> Table {
+        let obj: ItemStruct = syn::parse_str(model).unwrap();
+        let args: ModelArgs = syn::parse_str(args).unwrap();
+
+        struct_to_table(&obj, &args, &CustomTypes::default())
+    }
+
+    fn column<'a>(table: &'a Table, name: &str) -> &'a Column {
+        table.columns.iter().find(|column| column.name == name).unwrap()
+    }
+
+    #[test]
+    fn sqlite_types() {
+        assert_eq!(sqlite_type("SERIAL"), Some("INTEGER"));
+        assert_eq!(sqlite_type("BIGSERIAL"), Some("INTEGER"));
+        assert_eq!(sqlite_type("BYTEA"), Some("BLOB"));
+        assert_eq!(sqlite_type("VARCHAR(40)"), Some("VARCHAR(40)"));
+        assert_eq!(sqlite_type("TIMESTAMP"), Some("TIMESTAMP"));
+
+        for rejected in ["UUID", "JSONB", "NUMERIC", "TIMESTAMPTZ", "INTEGER[]"] {
+            assert_eq!(sqlite_type(rejected), None, "{}", rejected);
+        }
+    }
+
+    #[test]
+    fn foreign_keys_take_the_key_of_their_parent() {
+        let mut tables = vec![
+            table("struct Person { name: String }", "table = \"people\", primary_key = i64"),
+            table("struct Tag { #[primary_key] name: String }", ""),
+            table("#[belongs_to(Person)] #[belongs_to(Tag)] struct Pet { name: String }", ""),
+        ];
+
+        assert_eq!(column(&tables[2], "person_id").ty, "");
+
+        resolve_foreign_keys(&mut tables).unwrap();
+
+        let person_id = column(&tables[2], "person_id");
+        let tag_id = column(&tables[2], "tag_id");
+
+        assert_eq!(person_id.ty, "BIGINT");
+        assert_eq!(person_id.references.as_deref(), Some("people"));
+        assert_eq!(tag_id.ty, "TEXT");
+        assert_eq!(tag_id.references.as_deref(), Some("tags"));
+    }
+
+    #[test]
+    fn foreign_keys_to_a_composite_key_are_declared() {
+        let pair = "struct Pair { #[primary_key] a: i32, #[primary_key] b: i32 }";
+
+        let mut implicit = vec![table(pair, ""), table("#[belongs_to(Pair)] struct Child { x: i32 }", "")];
+        let mut missing = vec![table("#[belongs_to(Pair)] struct Child { x: i32 }", "")];
+        let mut declared = vec![
+            table(pair, ""),
+            table("#[belongs_to(Pair)] struct Child { pair_id: i32 }", ""),
+        ];
+
+        assert!(resolve_foreign_keys(&mut implicit).is_err());
+        assert!(resolve_foreign_keys(&mut missing).is_err());
+        assert!(resolve_foreign_keys(&mut declared).is_ok());
+    }
+
+    #[test]
+    fn snapshots_read_back_the_same_tables() {
+        let mut tables = vec![
+            table("struct User { #[unique] name: String }", ""),
+            table(
+                "#[belongs_to(User)] struct Todo { #[max_len(40)] #[index] title: String, \
+                 #[default(3)] priority: i32, note: Option<String> }",
+                "timestamps",
+            ),
+        ];
+
+        resolve_foreign_keys(&mut tables).unwrap();
+
+        assert_eq!(column(&tables[1], "title").ty, "VARCHAR(40)");
+        assert!(same_tables(&from_snapshot(&to_snapshot(&tables)), &tables));
+    }
+}
@@ -3,35 +3,83 @@ use proc_macro::TokenStream;
 use std::sync::{Arc, RwLock};
 
 use quote::quote;
-use syn::{parse::Result, parse_macro_input, ImplItem};
+use syn::{
+    parse::{Parse, ParseStream, Result},
+    parse_macro_input, ImplItem,
+};
+
+use crate::model_macro::MODELS;
 
 lazy_static! {
     // TODO: replace with atomics
     pub static ref QUERIES: Arc<RwLock<u64>> = Arc::new(RwLock::new(0));
 }
 
-pub fn perform(input: TokenStream) -> TokenStream {
+// #[sql(guard = my_guard)]
+#[derive(Default)]
+pub struct SqlArgs {
+    // Checked before watching a `#[watch]` query of the impl
+    pub guard: Option<syn::Expr>,
+}
+
+impl Parse for SqlArgs {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut args = SqlArgs::default();
+
+        while !input.is_empty() {
+            let name: syn::Ident = input.parse()?;
+
+            match name.to_string().as_str() {
+                "guard" => {
+                    input.parse::<syn::Token![=]>()?;
+
+                    args.guard = Some(input.parse()?);
+                }
+                _ => return Err(syn::Error::new(name.span(), "Unknown sql argument")),
+            }
+
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+pub fn perform(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as SqlArgs);
     let input = parse_macro_input!(input as syn::ItemImpl);
 
     proc_macro::TokenStream::from(
-        register_sql_queries(input).unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
+        register_sql_queries(args, input).unwrap_or_else(|e| syn::Error::to_compile_error(&e)),
     )
 }
 
-pub fn register_sql_queries(mut mcall: syn::ItemImpl) -> Result<proc_macro2::TokenStream> {
+pub fn register_sql_queries(args: SqlArgs, mut mcall: syn::ItemImpl) -> Result<proc_macro2::TokenStream> {
     mcall.attrs.push(syn::parse_quote! {
         #[rpc]
     });
 
-    // let self_type = *mcall.self_ty.clone();
+    let self_type = *mcall.self_ty.clone();
+
+    let mut watched = vec![];
+
     let res = mcall
         .items
         .iter()
         .map(|item| match item {
-            ImplItem::Method(method) => register_sql_query(method).unwrap(),
+            ImplItem::Method(method) if is_watch(method) => {
+                let (rpc, others) = register_watch_query(&self_type, method, args.guard.as_ref())?;
+
+                watched.push(others);
+
+                Ok(rpc)
+            }
+            ImplItem::Method(method) => register_sql_query(&self_type, method),
             _ => unimplemented!(),
         })
-        .collect::<Vec<_>>();
+        .collect::<Result<Vec<_>>>()?;
 
     mcall.items = res
         .iter()
@@ -42,29 +90,243 @@ pub fn register_sql_queries(mut mcall: syn::ItemImpl) -> Result<proc_macro2::Tok
     Ok(quote! {
         // #[rpc]
         #mcall
+
+        #(#watched)*
     })
 }
 
-pub fn register_sql_query(mcall: &syn::ImplItemMethod) -> Result<Vec<proc_macro2::TokenStream>> {
-    let mut server_fn = mcall.clone();
-    let client_fn = mcall.clone();
-    let mut stmts = server_fn.block.stmts.clone();
+fn is_watch(method: &syn::ImplItemMethod) -> bool {
+    method.attrs.iter().any(|attr| attr.path.is_ident("watch"))
+}
+
+// The body of a query method, executed server side
+fn query_block(mcall: &syn::ImplItemMethod) -> syn::Block {
+    let mut stmts = mcall.block.stmts.clone();
 
     let last = stmts.pop().unwrap();
-    let server_wrap: syn::Block = syn::parse_quote! { {
+
+    syn::parse_quote! { {
             #(#stmts)*
             let query = #last;
-             let mut conn = crate::establish_connection();
-            let res = query.load::<Self>(&mut conn).unwrap();
-            res
+
+            // The query may borrow the arguments, it runs in place while the other tasks move to another thread
+            comet::prelude::with_connection_blocking(|conn| {
+                query.load::<Self>(conn).map_err(comet::prelude::DbError::from)
+            })
+        }
+    }
+}
+
+// A #[watch] query returns a `Watched<Self>`, kept up to date by the server.
+// It is backed by an RPC that runs the query and subscribes the client to the changes of the model's table,
+// and another one to unsubscribe once the results are dropped
+pub fn register_watch_query(
+    self_type: &syn::Type,
+    mcall: &syn::ImplItemMethod,
+    impl_guard: Option<&syn::Expr>,
+) -> Result<(Vec<proc_macro2::TokenStream>, proc_macro2::TokenStream)> {
+    let mut mcall = mcall.clone();
+
+    // The method guards go to the RPC
+    let (guards, attrs): (Vec<_>, Vec<_>) = mcall
+        .attrs
+        .drain(..)
+        .filter(|attr| !attr.path.is_ident("watch"))
+        .partition(|attr| attr.path.is_ident("guard"));
+
+    mcall.attrs = attrs;
+
+    let name = mcall.sig.ident.clone();
+    let model_name = quote! { #self_type }.to_string();
+    let query_name = format!("{}::{}", model_name, name);
+
+    let query_fn_name: syn::Ident = syn::parse_str(&format!("__query_{}", name)).unwrap();
+    let watch_fn_name: syn::Ident = syn::parse_str(&format!("__watch_{}", name)).unwrap();
+    let unwatch_fn_name: syn::Ident = syn::parse_str(&format!("__unwatch_{}", name)).unwrap();
+    let guard_fn_name: syn::Ident = syn::parse_str(&format!("__can_watch_{}", name)).unwrap();
+
+    let args = mcall
+        .sig
+        .decl
+        .inputs
+        .iter()
+        .map(|arg| match arg {
+            syn::FnArg::Captured(c) => {
+                let pat = &c.pat;
+
+                quote! { #pat }
+            }
+            _ => unimplemented!("A watched query cannot take self"),
+        })
+        .collect::<Vec<_>>();
+
+    let args2 = args.clone();
+    let args3 = args.clone();
+    let args4 = args.clone();
+    let args5 = args.clone();
+    let args6 = args.clone();
+    let args7 = args.clone();
+    let args8 = args.clone();
+    let args9 = args.clone();
+
+    let mut query_fn = mcall.clone();
+
+    query_fn.attrs = vec![];
+    query_fn.sig.ident = query_fn_name.clone();
+    query_fn.sig.asyncness = None;
+    query_fn.sig.decl.output = syn::parse_quote! { -> Result<Vec<#self_type>, comet::prelude::DbError> };
+    query_fn.block = query_block(&mcall);
+
+    let mut watch_fn = query_fn.clone();
+
+    watch_fn.attrs = guards;
+    watch_fn.sig.ident = watch_fn_name.clone();
+    watch_fn.sig.asyncness = mcall.sig.asyncness;
+    watch_fn.sig.decl.output = syn::parse_quote! {
+        -> Result<(comet::prelude::QueryHash, Vec<(comet::prelude::ModelKey, #self_type)>), comet::prelude::DbError>
+    };
+    watch_fn.block = syn::parse_quote! { {
+            let query_hash = comet::prelude::query_hash(#query_name, &(#(#args.clone(),)*));
+
+            let models = comet::prelude::watch(query_hash, #self_type::TABLE, move || {
+                #(let #args2 = #args3.clone();)*
+
+                async move {
+                    Ok(#self_type::#query_fn_name(#(#args4),*)?
+                        .into_iter()
+                        .map(|model| (comet::prelude::PrimaryKey::model_key(&model), model))
+                        .collect())
+                }
+            })
+            .await?;
+
+            Ok((query_hash, models))
+        }
+    };
+
+    // The guard of the impl and the `can_list` rule of the model are checked before watching
+    let impl_guard = impl_guard.map(|guard| {
+        quote! {
+            if !(#guard)(context).await {
+                return false;
+            }
+        }
+    });
+
+    let guard_fn = quote! {
+        #[doc(hidden)]
+        pub async fn #guard_fn_name(context: &comet::prelude::RequestContext) -> bool {
+            #impl_guard
+
+            #self_type::__policy_can_list(context).await
+        }
+    };
+
+    let mut server_fn = mcall.clone();
+
+    server_fn.sig.decl.output =
+        syn::parse_quote! { -> Result<comet::prelude::Watched<#self_type>, comet::prelude::DbError> };
+
+    let mut client_fn = server_fn.clone();
+
+    // Server side, the results are not watched
+    server_fn.block = syn::parse_quote! { {
+            Ok(comet::prelude::Watched::new(
+                0,
+                #self_type::#query_fn_name(#(#args5),*)?
+                    .into_iter()
+                    .map(|model| (comet::prelude::PrimaryKey::model_key(&model), model))
+                    .collect(),
+            ))
+        }
+    };
+
+    // The pushed rows also refresh the client cache of the model
+    let model_id = MODELS
+        .read()
+        .unwrap()
+        .iter()
+        .position(|model| *model == model_name)
+        .ok_or_else(|| syn::Error::new_spanned(self_type, "A #[watch] query must be in a #[sql] block of a #[model]"))?
+        as u64;
+
+    // The server forgets the query once the last clone of the results is dropped
+    client_fn.block = syn::parse_quote! { {
+            let (query_hash, models) = #self_type::#watch_fn_name(#(#args6.clone()),*).await?;
+
+            let rewatch = move || {
+                #(let #args7 = #args8.clone();)*
+
+                async move {
+                    #self_type::#watch_fn_name(#(#args9),*)
+                        .await
+                        .map(|(_, models)| models)
+                }
+            };
+
+            // Only given the events the results could decode
+            let on_event = |event: &comet::prelude::WatchEvent| {
+                let models = match event.decode::<#self_type>() {
+                    Ok(models) => models.into_iter().map(|(_, model)| model.into()).collect(),
+                    Err(_) => return,
+                };
+
+                crate::CACHE.with(|cache| cache.borrow_mut().apply_watch_event(#model_id, models));
+            };
+
+            Ok(comet::prelude::watched(query_hash, models, rewatch, on_event, move || {
+                comet::prelude::spawn_local(async move {
+                    #self_type::#unwatch_fn_name(query_hash).await;
+                });
+            }))
         }
     };
 
-    // let mut sql_method = mcall.clone();
+    Ok((
+        vec![],
+        quote! {
+            #[cfg(not(target_arch = "wasm32"))]
+            impl #self_type {
+                #[doc(hidden)]
+                #query_fn
+
+                #guard_fn
+            }
+
+            #[rpc(guard = #self_type::#guard_fn_name)]
+            impl #self_type {
+                #watch_fn
+            }
+
+            #[rpc]
+            impl #self_type {
+                #[doc(hidden)]
+                pub async fn #unwatch_fn_name(query_hash: comet::prelude::QueryHash) {
+                    comet::prelude::unwatch(query_hash).await;
+                }
+            }
+
+            impl #self_type {
+                #[cfg(not(target_arch = "wasm32"))]
+                #server_fn
+
+                #[cfg(target_arch = "wasm32")]
+                #client_fn
+            }
+        },
+    ))
+}
+
+// A plain query returns `Result<Vec<Self>, DbError>` instead of the declared `Vec<Self>`,
+// the database errors are given to the caller
+pub fn register_sql_query(self_type: &syn::Type, mcall: &syn::ImplItemMethod) -> Result<Vec<proc_macro2::TokenStream>> {
+    let mut server_fn = mcall.clone();
+
+    server_fn.sig.decl.output = syn::parse_quote! { -> Result<Vec<#self_type>, comet::prelude::DbError> };
 
-    // sql_method.sig.decl.output = syn::ReturnType::Default;
+    let client_fn = server_fn.clone();
 
-    server_fn.block = server_wrap;
+    server_fn.block = query_block(mcall);
 
     Ok(vec![
         quote! {
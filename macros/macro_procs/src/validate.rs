@@ -0,0 +1,154 @@
+use quote::quote;
+use syn::parse::{Parse, ParseStream, Parser, Result};
+
+use crate::schema;
+
+// #[validate(length(min = 1, max = 20), email, range(min = 0, max = 100), custom(my_fn))] on a field
+pub enum Validation {
+    Length {
+        min: Option<syn::Expr>,
+        max: Option<syn::Expr>,
+    },
+    Email,
+    Range {
+        min: Option<syn::Expr>,
+        max: Option<syn::Expr>,
+    },
+    Custom(syn::Path),
+}
+
+impl Parse for Validation {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let name: syn::Ident = input.parse()?;
+
+        match name.to_string().as_str() {
+            "length" | "range" => {
+                let content;
+
+                syn::parenthesized!(content in input);
+
+                let mut min = None;
+                let mut max = None;
+
+                while !content.is_empty() {
+                    let bound: syn::Ident = content.parse()?;
+
+                    content.parse::<syn::Token![=]>()?;
+
+                    let value: syn::Expr = content.parse()?;
+
+                    match bound.to_string().as_str() {
+                        "min" => min = Some(value),
+                        "max" => max = Some(value),
+                        _ => return Err(syn::Error::new(bound.span(), "Expected min or max")),
+                    }
+
+                    if !content.is_empty() {
+                        content.parse::<syn::Token![,]>()?;
+                    }
+                }
+
+                if name == "length" {
+                    Ok(Validation::Length { min, max })
+                } else {
+                    Ok(Validation::Range { min, max })
+                }
+            }
+            "email" => Ok(Validation::Email),
+            "custom" => {
+                let content;
+
+                syn::parenthesized!(content in input);
+
+                Ok(Validation::Custom(content.parse()?))
+            }
+            _ => Err(syn::Error::new(
+                name.span(),
+                "Unknown validation, expected one of length, email, range, custom",
+            )),
+        }
+    }
+}
+
+pub fn is_validate_attr(attr: &syn::Attribute) -> bool {
+    attr.path.is_ident("validate")
+}
+
+pub fn parse(field: &syn::Field) -> Result<Vec<Validation>> {
+    let parser = |input: ParseStream| {
+        let content;
+
+        syn::parenthesized!(content in input);
+
+        Ok(content
+            .parse_terminated::<Validation, syn::Token![,]>(Validation::parse)?
+            .into_iter()
+            .collect::<Vec<_>>())
+    };
+
+    let mut validations = vec![];
+
+    for attr in field.attrs.iter().filter(|attr| is_validate_attr(attr)) {
+        validations.extend(parser.parse2(attr.tts.clone())?);
+    }
+
+    Ok(validations)
+}
+
+// The checks of a field, adding their messages to `errors`.
+// An `Option` field is only checked when it has a value
+pub fn field_checks(field: &syn::Field, validations: &[Validation]) -> proc_macro2::TokenStream {
+    let ident = field.ident.as_ref().unwrap();
+    let name = ident.to_string();
+
+    let option = |bound: &Option<syn::Expr>| match bound {
+        Some(bound) => quote! { Some(#bound) },
+        None => quote! { None },
+    };
+
+    let checks = validations
+        .iter()
+        .map(|validation| {
+            let check = match validation {
+                Validation::Length { min, max } => {
+                    let (min, max) = (option(min), option(max));
+
+                    quote! { comet::prelude::validate_length(value, #min, #max) }
+                }
+                Validation::Email => quote! { comet::prelude::validate_email(value) },
+                Validation::Range { min, max } => {
+                    let (min, max) = (option(min), option(max));
+
+                    quote! { comet::prelude::validate_range(value, #min, #max) }
+                }
+                Validation::Custom(path) => quote! { #path(value) },
+            };
+
+            quote! {
+                if let Err(message) = #check {
+                    errors.add(#name, message);
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if checks.is_empty() {
+        return quote! {};
+    }
+
+    if schema::generic_inner(&field.ty, "Option").is_some() {
+        quote! {
+            if let Some(value) = &self.#ident {
+                #(#checks)*
+            }
+        }
+    } else {
+        quote! {
+            {
+                let value = &self.#ident;
+
+                #(#checks)*
+            }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+// Name of the cookie holding the session token, sent along the websocket upgrade request
+pub const SESSION_COOKIE: &str = "comet_session";
+
+// Where the client exchanges a cookie grant for the session cookie, and clears it
+pub const SESSION_PATH: &str = "/session";
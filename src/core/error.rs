@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::validation::ValidationErrors;
+
+// Returned by a guarded RPC when its guard refused the call
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Forbidden;
+
+impl std::fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Forbidden")
+    }
+}
+
+impl std::error::Error for Forbidden {}
+
+impl From<Forbidden> for String {
+    fn from(forbidden: Forbidden) -> Self {
+        forbidden.to_string()
+    }
+}
+
+// The error of the model methods, sent as is to the client
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    NotFound,
+    // The column of the violated `#[unique]`, when it is known
+    UniqueViolation { column: Option<String> },
+    ForeignKeyViolation,
+    Validation(ValidationErrors),
+    Forbidden,
+    Connection(String),
+    Other(String),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DbError::NotFound => write!(f, "Not found"),
+            DbError::UniqueViolation {
+                column: Some(column),
+            } => {
+                write!(f, "The {} is already taken", column)
+            }
+            DbError::UniqueViolation { column: None } => write!(f, "Already exists"),
+            DbError::ForeignKeyViolation => write!(f, "A referenced model doesn't exist"),
+            DbError::Validation(errors) => write!(f, "Invalid: {}", errors),
+            DbError::Forbidden => write!(f, "Forbidden"),
+            DbError::Connection(message) => write!(f, "Database connection error: {}", message),
+            DbError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<Forbidden> for DbError {
+    fn from(_: Forbidden) -> Self {
+        DbError::Forbidden
+    }
+}
+
+impl From<ValidationErrors> for DbError {
+    fn from(errors: ValidationErrors) -> Self {
+        DbError::Validation(errors)
+    }
+}
+
+impl From<String> for DbError {
+    fn from(message: String) -> Self {
+        DbError::Other(message)
+    }
+}
+
+// For the methods still returning a `String` error
+impl From<DbError> for String {
+    fn from(error: DbError) -> Self {
+        error.to_string()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<diesel::result::Error> for DbError {
+    fn from(error: diesel::result::Error) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        match error {
+            Error::NotFound => DbError::NotFound,
+            // The constraints are named `{table}_{column}_key` by the migrations
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info) => {
+                let column = info
+                    .column_name()
+                    .map(str::to_string)
+                    .or_else(|| {
+                        let prefix = format!("{}_", info.table_name()?);
+
+                        info.constraint_name()?
+                            .strip_prefix(&prefix)?
+                            .strip_suffix("_key")
+                            .map(str::to_string)
+                    })
+                    // SQLite only gives it in the message, `UNIQUE constraint failed: {table}.{column}`
+                    .or_else(|| {
+                        let (_, column) = info
+                            .message()
+                            .strip_prefix("UNIQUE constraint failed: ")?
+                            .split_once('.')?;
+
+                        Some(column.to_string()).filter(|column| !column.contains(','))
+                    });
+
+                DbError::UniqueViolation { column }
+            }
+            Error::DatabaseError(DatabaseErrorKind::ForeignKeyViolation, _) => {
+                DbError::ForeignKeyViolation
+            }
+            Error::DatabaseError(DatabaseErrorKind::ClosedConnection, info) => {
+                DbError::Connection(info.message().to_string())
+            }
+            other => DbError::Other(other.to_string()),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<diesel::ConnectionError> for DbError {
+    fn from(error: diesel::ConnectionError) -> Self {
+        DbError::Connection(error.to_string())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<diesel::r2d2::PoolError> for DbError {
+    fn from(error: diesel::r2d2::PoolError) -> Self {
+        DbError::Connection(error.to_string())
+    }
+}
@@ -1,24 +1,13 @@
 mod app;
+mod auth;
 pub mod component;
+mod error;
+mod model;
 mod proto;
+mod query;
 mod shared;
 mod utils;
+mod validation;
+mod watch;
 
 pub mod prelude;
-
-mod just_to_test {
-    use crate::prelude::*;
-
-    pub struct Counter {
-        value: i32,
-    }
-
-    component! {
-        Counter,
-        button @click: { self.value += 1 } {
-            for i in (0..10) {
-                { i }
-            }
-        }
-    }
-}
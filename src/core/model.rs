@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+// Identifies a model in the client cache and in the watched queries: its primary key, with `Debug`
+pub type ModelKey = String;
+
+// Implemented by every `#[model]`, whatever its primary key
+pub trait PrimaryKey {
+    type Key: Clone + PartialEq + Debug;
+
+    // None until the model is saved, for the keys given by the database
+    fn primary_key(&self) -> Option<Self::Key>;
+
+    fn model_key(&self) -> ModelKey {
+        self.primary_key().map(|key| model_key(&key)).unwrap_or_default()
+    }
+}
+
+pub fn model_key<K: Debug>(key: &K) -> ModelKey {
+    format!("{:?}", key)
+}
@@ -5,29 +5,40 @@ pub use derive_more::*;
 pub use async_trait::async_trait;
 pub use lazy_static::lazy_static;
 
+pub use chrono;
 pub use diesel;
 pub use diesel::prelude::*;
 
 pub use paste::paste;
+pub use rust_decimal;
 pub use serde;
 pub use serde::{Deserialize, Serialize};
 pub use serde_cbor;
+pub use serde_json;
 pub use std::sync::Arc;
 pub use std::{cell::RefCell, rc::Rc};
 pub use tokio::sync::RwLock;
+pub use uuid;
 pub use web_sys;
 
 pub use crate::core::app::*;
+pub use crate::core::auth::*;
 pub use crate::core::component::*;
+pub use crate::core::error::*;
+pub use crate::core::model::*;
+pub use crate::core::query::*;
 pub use crate::core::shared::*;
 
 pub use crate::core::component::run_rec;
-pub use crate::core::proto::{Message, ProtoTrait};
+pub use crate::core::proto::{Message, ProtoTrait, Push, RoomEvent, WatchEvent, PUSH_REQUEST_ID};
 pub use crate::core::utils::*;
+pub use crate::core::validation::*;
+pub use crate::core::watch::*;
 
 // macros
 pub use crate::_gen_full_variant;
 pub use crate::_gen_variant;
+pub use crate::auth;
 pub use crate::component;
 pub use crate::extract_bindings;
 pub use crate::extract_msg;
@@ -38,10 +49,15 @@ pub use crate::html;
 pub use crate::html_arr;
 pub use crate::replace_self;
 pub use crate::run;
+pub use comet_macro_procs::auth_models;
 pub use comet_macro_procs::db;
+pub use comet_macro_procs::db_enum;
 pub use comet_macro_procs::generate_migrations;
+pub use comet_macro_procs::generate_model_enum;
 pub use comet_macro_procs::generate_proto;
 pub use comet_macro_procs::generate_rpc_proto;
+pub use comet_macro_procs::generate_schema;
+pub use comet_macro_procs::json;
 pub use comet_macro_procs::model;
 pub use comet_macro_procs::rpc;
 pub use comet_macro_procs::sql;
@@ -3,10 +3,19 @@ use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 
+use super::{model::ModelKey, watch::QueryHash};
+
+// Request id reserved for the messages pushed by the server without being asked
+// (room broadcasts and watched queries changes)
+pub const PUSH_REQUEST_ID: u64 = u64::MAX;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub request_id: u64,
     pub msg: Vec<u8>,
+    // Sent by the client: the request ids of the responses it received, the server can forget them
+    #[serde(default)]
+    pub acks: Vec<u64>,
 }
 
 impl Message {
@@ -14,12 +23,104 @@ impl Message {
         serde_cbor::to_vec(self).unwrap()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        serde_cbor::from_slice(bytes).unwrap()
+    // The frames come from the other side of the socket, a malformed one is an error and not a panic
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(bytes)
+    }
+
+    pub fn push(msg: Vec<u8>) -> Self {
+        Self {
+            request_id: PUSH_REQUEST_ID,
+            msg,
+            acks: vec![],
+        }
+    }
+
+    // Only acknowledges the responses, without a query
+    pub fn ack(request_ids: Vec<u64>) -> Self {
+        Self {
+            request_id: PUSH_REQUEST_ID,
+            msg: vec![],
+            acks: request_ids,
+        }
+    }
+
+    pub fn is_push(&self) -> bool {
+        self.request_id == PUSH_REQUEST_ID
+    }
+}
+
+// A payload broadcasted to every member of a room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomEvent {
+    pub room: String,
+    pub payload: Vec<u8>,
+}
+
+impl RoomEvent {
+    pub fn new<T: Serialize>(room: &str, payload: &T) -> Self {
+        Self {
+            room: room.to_string(),
+            payload: serde_cbor::to_vec(payload).unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(bytes)
+    }
+
+    // Fails when the payload isn't a `T`, like a message sent to the room by another version of the app
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T, serde_cbor::Error> {
+        serde_cbor::from_slice(&self.payload)
+    }
+}
+
+// A change in the results of a watched query, pushed to the clients watching it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchEvent {
+    pub query_hash: QueryHash,
+    // The new and updated models, with their key
+    pub models: Vec<(ModelKey, Vec<u8>)>,
+    pub deleted_ids: Vec<ModelKey>,
+    // The keys of all the results, in the order of the query
+    pub order: Vec<ModelKey>,
+}
+
+impl WatchEvent {
+    // Fails when a model isn't a `T`, like a change sent by another version of the app
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<Vec<(ModelKey, T)>, serde_cbor::Error> {
+        self.models
+            .iter()
+            .map(|(id, model)| Ok((id.clone(), serde_cbor::from_slice(model)?)))
+            .collect()
+    }
+}
+
+// Everything the server sends without being asked
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Push {
+    Room(RoomEvent),
+    Event(WatchEvent),
+    // Sent first on every connection: once reconnected, the client gives it back to rejoin the rooms of this connection
+    Resume(String),
+}
+
+impl Push {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_cbor::Error> {
+        serde_cbor::from_slice(bytes)
     }
 }
 
-#[async_trait]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 pub trait ProtoTrait {
     type Response: ProtoTrait + Send + Serialize + DeserializeOwned;
 
@@ -27,11 +128,17 @@ pub trait ProtoTrait {
     where
         Self: Sized;
 
-    fn from_bytes(bytes: &[u8]) -> Self
+    // The error returned by the RPC, if this is a failed response
+    fn error(&self) -> Option<String> {
+        None
+    }
+
+    // The bytes come from the other side of the socket
+    fn from_bytes(bytes: &[u8]) -> Result<Self, serde_cbor::Error>
     where
         Self: Sized + DeserializeOwned,
     {
-        serde_cbor::from_slice(bytes).unwrap()
+        serde_cbor::from_slice(bytes)
     }
 
     fn to_bytes(&self) -> Vec<u8>
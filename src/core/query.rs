@@ -0,0 +1,390 @@
+use std::{fmt, marker::PhantomData};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::core::error::DbError;
+
+// The most results a query can ask for at once, larger pages are refused by the server
+pub const MAX_LIMIT: i64 = 1000;
+
+// The results of a query without a limit
+pub const DEFAULT_LIMIT: i64 = 100;
+
+// A column of the model `M`, holding values of type `T`.
+// Generated for every stored field of a model, in the `<model>_columns` module
+pub struct ModelColumn<M, T> {
+    name: &'static str,
+    _marker: PhantomData<fn() -> (M, T)>,
+}
+
+impl<M, T> Clone for ModelColumn<M, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M, T> Copy for ModelColumn<M, T> {}
+
+impl<M, T: Serialize> ModelColumn<M, T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn compare(&self, op: FilterOp, value: T) -> Filter<M> {
+        Filter {
+            column: self.name.to_string(),
+            op,
+            value: Some(serde_json::to_value(value).unwrap()),
+            _model: PhantomData,
+        }
+    }
+
+    pub fn eq(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Eq, value)
+    }
+
+    pub fn ne(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Ne, value)
+    }
+
+    pub fn lt(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Lt, value)
+    }
+
+    pub fn le(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Le, value)
+    }
+
+    pub fn gt(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Gt, value)
+    }
+
+    pub fn ge(&self, value: T) -> Filter<M> {
+        self.compare(FilterOp::Ge, value)
+    }
+
+    pub fn is_null(&self) -> Filter<M> {
+        Filter {
+            column: self.name.to_string(),
+            op: FilterOp::IsNull,
+            value: None,
+            _model: PhantomData,
+        }
+    }
+
+    pub fn is_not_null(&self) -> Filter<M> {
+        Filter {
+            column: self.name.to_string(),
+            op: FilterOp::IsNotNull,
+            value: None,
+            _model: PhantomData,
+        }
+    }
+
+    pub fn asc(&self) -> Order<M> {
+        Order {
+            column: self.name.to_string(),
+            desc: false,
+            _model: PhantomData,
+        }
+    }
+
+    pub fn desc(&self) -> Order<M> {
+        Order {
+            column: self.name.to_string(),
+            desc: true,
+            _model: PhantomData,
+        }
+    }
+}
+
+// SQL `LIKE`, with `%` and `_` wildcards
+impl<M> ModelColumn<M, String> {
+    pub fn like(&self, pattern: impl Into<String>) -> Filter<M> {
+        self.compare(FilterOp::Like, pattern.into())
+    }
+}
+
+impl<M> ModelColumn<M, Option<String>> {
+    pub fn like(&self, pattern: impl Into<String>) -> Filter<M> {
+        self.compare(FilterOp::Like, Some(pattern.into()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Like,
+    IsNull,
+    IsNotNull,
+}
+
+// Sent as is to the server, that checks the column against the ones of the model
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Filter<M> {
+    pub column: String,
+    pub op: FilterOp,
+    value: Option<serde_json::Value>,
+    #[serde(skip)]
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M> Filter<M> {
+    // The compared value, as the type of the column
+    pub fn value<T: DeserializeOwned>(&self) -> Result<T, String> {
+        let value = self.value.clone().unwrap_or(serde_json::Value::Null);
+
+        serde_json::from_value(value)
+            .map_err(|_| format!("Invalid value for the column {}", self.column))
+    }
+}
+
+impl<M> Clone for Filter<M> {
+    fn clone(&self) -> Self {
+        Self {
+            column: self.column.clone(),
+            op: self.op,
+            value: self.value.clone(),
+            _model: PhantomData,
+        }
+    }
+}
+
+impl<M> fmt::Debug for Filter<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:?} {:?}", self.column, self.op, self.value)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Order<M> {
+    pub column: String,
+    pub desc: bool,
+    #[serde(skip)]
+    _model: PhantomData<fn() -> M>,
+}
+
+impl<M> Clone for Order<M> {
+    fn clone(&self) -> Self {
+        Self {
+            column: self.column.clone(),
+            desc: self.desc,
+            _model: PhantomData,
+        }
+    }
+}
+
+impl<M> fmt::Debug for Order<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            self.column,
+            if self.desc { "DESC" } else { "ASC" }
+        )
+    }
+}
+
+// A bare column is sorted in ascending order
+impl<M, T: Serialize> From<ModelColumn<M, T>> for Order<M> {
+    fn from(column: ModelColumn<M, T>) -> Self {
+        column.asc()
+    }
+}
+
+// The filters, sorting and limits of a `Model::query()`, loaded with `load()`.
+// The results are then sorted by primary key
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ModelQuery<M> {
+    pub filters: Vec<Filter<M>>,
+    pub order: Vec<Order<M>>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl<M> Default for ModelQuery<M> {
+    fn default() -> Self {
+        Self {
+            filters: vec![],
+            order: vec![],
+            limit: None,
+            offset: None,
+        }
+    }
+}
+
+impl<M> Clone for ModelQuery<M> {
+    fn clone(&self) -> Self {
+        Self {
+            filters: self.filters.clone(),
+            order: self.order.clone(),
+            limit: self.limit,
+            offset: self.offset,
+        }
+    }
+}
+
+impl<M> fmt::Debug for ModelQuery<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelQuery")
+            .field("filters", &self.filters)
+            .field("order", &self.order)
+            .field("limit", &self.limit)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl<M> ModelQuery<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Every filter must match
+    pub fn filter(mut self, filter: Filter<M>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn order_by(mut self, order: impl Into<Order<M>>) -> Self {
+        self.order.push(order.into());
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    // Rejected by the server before running anything
+    pub fn check_limits(&self) -> Result<(), String> {
+        if self.limit.map(|limit| limit < 0).unwrap_or(false)
+            || self.offset.map(|offset| offset < 0).unwrap_or(false)
+        {
+            return Err("Invalid limit or offset".to_string());
+        }
+
+        if self.limit.map(|limit| limit > MAX_LIMIT).unwrap_or(false) {
+            return Err(format!("The limit can't be more than {}", MAX_LIMIT));
+        }
+
+        Ok(())
+    }
+
+    // The limit run on the server, `DEFAULT_LIMIT` when none is given
+    pub fn effective_limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+}
+
+impl<M: QueryLoad> ModelQuery<M> {
+    pub async fn load(self) -> Result<Vec<M>, DbError> {
+        M::load_query(self).await
+    }
+}
+
+// Implemented by the models with a `list` endpoint, to run their queries
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait QueryLoad: Sized {
+    async fn load_query(query: ModelQuery<Self>) -> Result<Vec<Self>, DbError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Todo;
+
+    const TITLE: ModelColumn<Todo, String> = ModelColumn::new("title");
+    const DUE: ModelColumn<Todo, Option<i32>> = ModelColumn::new("due");
+
+    // As sent by the client to the server
+    fn round_trip(query: ModelQuery<Todo>) -> ModelQuery<Todo> {
+        serde_cbor::from_slice(&serde_cbor::to_vec(&query).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn filters_and_order_survive_the_wire() {
+        let query = round_trip(
+            ModelQuery::new()
+                .filter(TITLE.like("%milk%"))
+                .filter(DUE.gt(Some(3)))
+                .filter(DUE.is_null())
+                .order_by(TITLE)
+                .order_by(DUE.desc())
+                .limit(20)
+                .offset(40),
+        );
+
+        assert_eq!(query.filters[0].column, "title");
+        assert_eq!(query.filters[0].op, FilterOp::Like);
+        assert_eq!(query.filters[0].value::<String>(), Ok("%milk%".to_string()));
+
+        assert_eq!(query.filters[1].op, FilterOp::Gt);
+        assert_eq!(query.filters[1].value::<Option<i32>>(), Ok(Some(3)));
+
+        assert_eq!(query.filters[2].op, FilterOp::IsNull);
+        assert_eq!(query.filters[2].value::<Option<i32>>(), Ok(None));
+
+        assert_eq!(
+            (query.order[0].column.as_str(), query.order[0].desc),
+            ("title", false)
+        );
+        assert_eq!(
+            (query.order[1].column.as_str(), query.order[1].desc),
+            ("due", true)
+        );
+
+        assert_eq!((query.limit, query.offset), (Some(20), Some(40)));
+    }
+
+    #[test]
+    fn values_of_the_wrong_type_are_refused() {
+        let filter = TITLE.eq("milk".to_string());
+
+        assert_eq!(
+            filter.value::<i32>(),
+            Err("Invalid value for the column title".to_string())
+        );
+    }
+
+    #[test]
+    fn limits_are_checked() {
+        let query = ModelQuery::<Todo>::new;
+
+        assert!(query().check_limits().is_ok());
+        assert!(query().limit(MAX_LIMIT).check_limits().is_ok());
+
+        assert!(query().limit(MAX_LIMIT + 1).check_limits().is_err());
+        assert!(query().limit(-1).check_limits().is_err());
+        assert!(query().offset(-1).check_limits().is_err());
+    }
+
+    #[test]
+    fn queries_without_limit_get_the_default() {
+        assert_eq!(ModelQuery::<Todo>::new().effective_limit(), DEFAULT_LIMIT);
+        assert_eq!(ModelQuery::<Todo>::new().limit(5).effective_limit(), 5);
+    }
+}
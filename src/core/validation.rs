@@ -0,0 +1,210 @@
+use std::{collections::BTreeMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+// Implemented by every model, from the `#[validate(...)]` attributes of its fields.
+// Runs on the client before sending, and on the server before writing
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+// The error messages of every invalid field, by field name
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValidationErrors {
+    fields: BTreeMap<String, Vec<String>>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.fields
+            .entry(field.to_string())
+            .or_default()
+            .push(message.into());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn fields(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.fields
+    }
+
+    pub fn field(&self, field: &str) -> &[String] {
+        self.fields.get(field).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    // The messages of a field, empty when it is valid. Meant to be displayed next to its input
+    pub fn message(&self, field: &str) -> String {
+        self.field(field).join(", ")
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(field, messages)| format!("{}: {}", field, messages.join(", ")))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", fields.join("; "))
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl From<ValidationErrors> for String {
+    fn from(errors: ValidationErrors) -> Self {
+        errors.to_string()
+    }
+}
+
+// The length of a text in characters, or of a list
+pub trait HasLength {
+    fn length(&self) -> usize;
+}
+
+impl HasLength for str {
+    fn length(&self) -> usize {
+        self.chars().count()
+    }
+}
+
+impl HasLength for String {
+    fn length(&self) -> usize {
+        self.as_str().length()
+    }
+}
+
+impl<T> HasLength for Vec<T> {
+    fn length(&self) -> usize {
+        self.len()
+    }
+}
+
+pub fn validate_length<T: HasLength + ?Sized>(
+    value: &T,
+    min: Option<usize>,
+    max: Option<usize>,
+) -> Result<(), String> {
+    let length = value.length();
+
+    match (min, max) {
+        (Some(min), _) if length < min && min == 1 => Err("must not be empty".to_string()),
+        (Some(min), _) if length < min => Err(format!("must be at least {} long", min)),
+        (_, Some(max)) if length > max => Err(format!("must be at most {} long", max)),
+        _ => Ok(()),
+    }
+}
+
+// Only checks the shape of the address, not that it exists
+pub fn validate_email(value: &str) -> Result<(), String> {
+    let valid = match value.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !value.chars().any(char::is_whitespace)
+        }
+        None => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err("must be an email address".to_string())
+    }
+}
+
+pub fn validate_range<T: PartialOrd + fmt::Display>(
+    value: &T,
+    min: Option<T>,
+    max: Option<T>,
+) -> Result<(), String> {
+    match (min, max) {
+        (Some(min), _) if *value < min => Err(format!("must be at least {}", min)),
+        (_, Some(max)) if *value > max => Err(format!("must be at most {}", max)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_bounds_are_inclusive() {
+        assert_eq!(
+            validate_length("", Some(1), None),
+            Err("must not be empty".to_string())
+        );
+        assert!(validate_length("", None, Some(3)).is_ok());
+
+        assert!(validate_length("ab", Some(2), Some(4)).is_ok());
+        assert!(validate_length("abcd", Some(2), Some(4)).is_ok());
+        assert_eq!(
+            validate_length("a", Some(2), Some(4)),
+            Err("must be at least 2 long".to_string())
+        );
+        assert_eq!(
+            validate_length("abcde", Some(2), Some(4)),
+            Err("must be at most 4 long".to_string())
+        );
+    }
+
+    #[test]
+    fn length_counts_characters() {
+        // 4 characters, 8 bytes
+        assert!(validate_length("été!", Some(4), Some(4)).is_ok());
+        assert!(validate_length("日本", None, Some(2)).is_ok());
+        assert!(validate_length("日本語", None, Some(2)).is_err());
+
+        assert!(validate_length(&vec![1, 2], Some(2), Some(2)).is_ok());
+    }
+
+    #[test]
+    fn email_needs_a_local_part_and_a_dotted_domain() {
+        assert!(validate_email("jane@example.com").is_ok());
+        assert!(validate_email("a@b.c").is_ok());
+
+        assert!(validate_email("").is_err());
+        assert!(validate_email("a@b").is_err());
+        assert!(validate_email("@b.c").is_err());
+        assert!(validate_email("a@.c").is_err());
+        assert!(validate_email("a@b.").is_err());
+        assert!(validate_email("a@b@c.d").is_err());
+        assert!(validate_email("a b@c.d").is_err());
+    }
+
+    #[test]
+    fn range_bounds_are_inclusive() {
+        assert!(validate_range(&1, Some(1), Some(10)).is_ok());
+        assert!(validate_range(&10, Some(1), Some(10)).is_ok());
+        assert_eq!(
+            validate_range(&0, Some(1), Some(10)),
+            Err("must be at least 1".to_string())
+        );
+        assert_eq!(
+            validate_range(&11, Some(1), Some(10)),
+            Err("must be at most 10".to_string())
+        );
+
+        assert!(validate_range(&-5.5, None, Some(0.0)).is_ok());
+        assert!(validate_range(&0.5, None, Some(0.0)).is_err());
+    }
+}
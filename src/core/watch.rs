@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use serde::de::DeserializeOwned;
+
+use super::{model::ModelKey, proto::WatchEvent};
+
+// Identifies a watched query along with its parameters
+pub type QueryHash = u64;
+
+// Runs its callback once dropped, shared by the clones of a `Watched`
+pub struct Subscription {
+    unsubscribe: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Subscription {
+    pub fn new<F: FnOnce() + Send + Sync + 'static>(unsubscribe: F) -> Self {
+        Self {
+            unsubscribe: Some(Box::new(unsubscribe)),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+impl std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Subscription").finish()
+    }
+}
+
+// The results of a `#[watch]` query.
+// Client side, they are kept up to date by the server and the app is redrawn on every change,
+// until the last clone is dropped
+#[derive(Debug)]
+pub struct Watched<T> {
+    pub query_hash: QueryHash,
+    models: Arc<RwLock<Vec<(ModelKey, T)>>>,
+    subscription: Option<Arc<Subscription>>,
+}
+
+impl<T> Clone for Watched<T> {
+    fn clone(&self) -> Self {
+        Self {
+            query_hash: self.query_hash,
+            models: self.models.clone(),
+            subscription: self.subscription.clone(),
+        }
+    }
+}
+
+impl<T> Default for Watched<T> {
+    fn default() -> Self {
+        Self::new(0, vec![])
+    }
+}
+
+impl<T> Watched<T> {
+    pub fn new(query_hash: QueryHash, models: Vec<(ModelKey, T)>) -> Self {
+        Self {
+            query_hash,
+            models: Arc::new(RwLock::new(models)),
+            subscription: None,
+        }
+    }
+
+    // Ties the subscription to the results, it ends when the last clone is dropped
+    pub fn subscribed(mut self, subscription: Subscription) -> Self {
+        self.subscription = Some(Arc::new(subscription));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.models.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone> Watched<T> {
+    // A snapshot of the current results
+    pub fn get(&self) -> Vec<T> {
+        self.models
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, model)| model.clone())
+            .collect()
+    }
+}
+
+impl<T: DeserializeOwned> Watched<T> {
+    // An event that can't be decoded leaves the results as they were
+    pub fn apply(&self, event: &WatchEvent) -> Result<(), serde_cbor::Error> {
+        let changed = event.decode::<T>()?;
+
+        let mut models = self.models.write().unwrap();
+
+        let mut by_id = models.drain(..).collect::<HashMap<_, _>>();
+
+        for id in &event.deleted_ids {
+            by_id.remove(id);
+        }
+
+        by_id.extend(changed);
+
+        *models = event
+            .order
+            .iter()
+            .filter_map(|id| by_id.remove(id).map(|model| (id.clone(), model)))
+            .collect();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+// Generates the `User` and `Session` models, their authentication RPCs,
+// and an `auth` module with `register`, `login`, `logout` and `current_user`.
+// Must be called in the root module, before `comet::run!`.
+// `crate::schema` is the module `comet::run!` generates in the calling crate, not one of Comet
+#[allow(clippy::crate_in_macro_def)]
+#[macro_export]
+macro_rules! auth {
+    () => {
+        // The models are shared with the `comet` binary, that generates their migrations
+        comet::prelude::auth_models! {}
+
+        #[cfg(not(target_arch = "wasm32"))]
+        impl User {
+            fn without_password(mut self) -> Self {
+                self.password_hash = "".to_string();
+                self
+            }
+
+            async fn by_username(username_given: &str) -> Option<User> {
+                use crate::schema::users;
+
+                let username_given = username_given.to_string();
+
+                comet::prelude::with_connection(move |conn| {
+                    Ok(users::table
+                        .filter(users::dsl::username.eq(username_given))
+                        .first::<User>(conn)
+                        .optional()?)
+                })
+                .await
+                .unwrap_or(None)
+            }
+
+            // Persist a new session for this user, and authenticate the calling client with it.
+            // The client only gets a grant to exchange for the session cookie, never the token
+            async fn open_session(self) -> Result<(User, String), String> {
+                use crate::schema::sessions;
+
+                let token = comet::prelude::new_session_token();
+                let user_id = self.id.ok_or_else(|| "Unsaved user".to_string())?;
+
+                // The expired sessions are cleaned up along the way
+                let now = comet::prelude::chrono::Utc::now().naive_utc();
+
+                comet::prelude::with_connection(move |conn| {
+                    Ok(diesel::delete(sessions::table.filter(sessions::dsl::expires_at.lt(now)))
+                        .execute(conn)?)
+                })
+                .await?;
+
+                Session {
+                    id: None,
+                    token: token.clone(),
+                    user_id,
+                    expires_at: comet::prelude::session_expiry(),
+                }
+                .create()
+                .await?;
+
+                let context = comet::prelude::request_context();
+
+                context.set_user_id(Some(user_id));
+                context.set_session_token(Some(token.clone()));
+
+                Ok((self.without_password(), comet::prelude::grant_session_cookie(token)))
+            }
+        }
+
+        #[rpc]
+        impl User {
+            pub async fn auth_register(username: String, password: String) -> Result<(User, String), String> {
+                let user = User {
+                    id: None,
+                    username,
+                    password_hash: comet::prelude::hash_password(&password),
+                }
+                .create()
+                .await;
+
+                match user {
+                    Ok(user) => user.open_session().await,
+                    // The username is unique in the database, concurrent registrations can't both get it
+                    Err(comet::prelude::DbError::UniqueViolation { .. }) => {
+                        Err("Username already taken".to_string())
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
+
+            pub async fn auth_login(username: String, password: String) -> Result<(User, String), String> {
+                match User::by_username(&username).await {
+                    Some(user) if comet::prelude::verify_password(&password, &user.password_hash) => {
+                        user.open_session().await
+                    }
+                    _ => Err("Invalid username or password".to_string()),
+                }
+            }
+
+            pub async fn auth_logout() {
+                use crate::schema::sessions;
+
+                let context = comet::prelude::request_context();
+
+                if let Some(token) = context.session_token() {
+                    let _ = comet::prelude::with_connection(move |conn| {
+                        Ok(diesel::delete(sessions::table.filter(sessions::dsl::token.eq(token)))
+                            .execute(conn)?)
+                    })
+                    .await;
+                }
+
+                context.set_user_id(None);
+                context.set_session_token(None);
+            }
+
+            pub async fn auth_current() -> Option<User> {
+                let user_id = comet::prelude::request_context().user_id()?;
+
+                User::fetch(user_id).await.ok().map(User::without_password)
+            }
+        }
+
+        pub mod auth {
+            use super::*;
+
+            // Resolves a session token into its user id, to give to `ServerConfig::authenticate`.
+            // An expired session resolves to nothing
+            #[cfg(not(target_arch = "wasm32"))]
+            pub async fn authenticate(token_given: String) -> Option<i32> {
+                use crate::schema::sessions;
+
+                let now = comet::prelude::chrono::Utc::now().naive_utc();
+
+                comet::prelude::with_connection(move |conn| {
+                    Ok(sessions::table
+                        .filter(sessions::dsl::token.eq(token_given))
+                        .filter(sessions::dsl::expires_at.gt(now))
+                        .first::<Session>(conn)?)
+                })
+                .await
+                .ok()
+                .map(|session| session.user_id)
+            }
+
+            // The user of the client whose request is being handled
+            #[cfg(not(target_arch = "wasm32"))]
+            pub async fn current_user() -> Option<User> {
+                let user_id = comet::prelude::try_request_context()?.user_id()?;
+
+                User::fetch(user_id).await.ok().map(User::without_password)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            thread_local! {
+                // None until the server has been asked once
+                static CURRENT_USER: RefCell<Option<Option<User>>> = RefCell::new(None);
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            fn set_current_user(user: Option<User>) {
+                CURRENT_USER.with(|current| *current.borrow_mut() = Some(user));
+            }
+
+            // The logged in user, fetched from the server on the first call only
+            #[cfg(target_arch = "wasm32")]
+            pub async fn current_user() -> Option<User> {
+                if let Some(user) = CURRENT_USER.with(|current| current.borrow().clone()) {
+                    return user;
+                }
+
+                let user = User::auth_current().await;
+
+                set_current_user(user.clone());
+
+                user
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            pub async fn register(username: String, password: String) -> Result<User, String> {
+                let (user, grant) = User::auth_register(username, password).await?;
+
+                store_session(&grant).await?;
+                set_current_user(Some(user.clone()));
+
+                Ok(user)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            pub async fn login(username: String, password: String) -> Result<User, String> {
+                let (user, grant) = User::auth_login(username, password).await?;
+
+                store_session(&grant).await?;
+                set_current_user(Some(user.clone()));
+
+                Ok(user)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            pub async fn logout() {
+                User::auth_logout().await;
+
+                clear_session().await;
+                set_current_user(None);
+            }
+        }
+    };
+}
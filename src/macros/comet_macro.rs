@@ -1,16 +1,17 @@
 #[macro_export]
 macro_rules! run {
-    ($($e:tt)+) => {
+    ($root:expr) => {
+        comet::run!($root, ServerConfig::default());
+    };
+    // The second argument is a `ServerConfig`, only evaluated server side
+    ($root:expr, $config:expr) => {
 
         pub use comet::prelude::*;
 
         #[cfg(target_arch = "wasm32")]
         use std::panic;
 
-        // #[cfg(not(target_arch = "wasm32"))]
-        mod schema;
-        // #[cfg(not(target_arch = "wasm32"))]
-        // use schema::*;
+        generate_schema! {}
 
         generate_rpc_proto! {}
         generate_proto! {}
@@ -43,7 +44,16 @@ macro_rules! run {
 
             ready_rx.await.unwrap();
 
-            comet::_run($($e)+).await;
+            comet::_run($root).await;
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        generate_model_enum! {}
+
+        #[cfg(target_arch = "wasm32")]
+        thread_local! {
+            // The models fetched by the client
+            pub static CACHE: RefCell<Cache<Model>> = RefCell::new(Cache::new());
         }
 
         #[cfg(target_arch = "wasm32")]
@@ -66,28 +76,32 @@ macro_rules! run {
             ready.send(()).unwrap();
 
             while let Some(packet) = rx.next().await {
-                comet::console_log!("packet {:#?}", packet);
+                if packet.is_push() {
+                    // bad push
+                    if let Ok(push) = Push::from_bytes(&packet.msg) {
+                        handle_push(push);
+                    }
+                } else {
+                    comet::console_log!("packet {:#?}", packet);
+                }
             }
         }
 
         #[cfg(not(target_arch = "wasm32"))]
-        use crate::diesel::pg::PgConnection;
-
-        #[cfg(not(target_arch = "wasm32"))]
-        pub fn establish_connection() -> PgConnection {
+        pub fn establish_connection() -> comet::prelude::DbRawConnection {
             use crate::diesel::prelude::*;
             use std::env;
 
             let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
-            PgConnection::establish(&database_url)
+            comet::prelude::DbRawConnection::establish(&database_url)
                 .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
         }
 
         #[cfg(not(target_arch = "wasm32"))]
         #[tokio::main]
         pub async fn main() {
-                comet::server::server::run::<Proto>().await;
+            comet::server::server::run::<Proto>($config).await;
         }
     }
 }
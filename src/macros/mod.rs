@@ -1,3 +1,4 @@
+mod auth_macro;
 mod bindings_macro;
 mod comet_macro;
 mod component;
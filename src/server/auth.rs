@@ -0,0 +1,116 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    http::{header::SET_COOKIE, StatusCode},
+    response::{IntoResponse, Response},
+};
+use lazy_static::lazy_static;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::config::ServerConfig;
+use crate::core::prelude::SESSION_COOKIE;
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+pub fn new_session_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect()
+}
+
+// A grant not exchanged for its cookie after that long is forgotten
+const GRANT_TIMEOUT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // Set by `server::run` from the config
+    static ref SESSION_DURATION: RwLock<Duration> = RwLock::new(ServerConfig::default().session_duration);
+    // The session tokens waiting for their client to exchange them for the session cookie, by grant
+    static ref GRANTS: Mutex<HashMap<String, (String, Instant)>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) fn init(config: &ServerConfig) {
+    *SESSION_DURATION.write().unwrap() = config.session_duration;
+}
+
+// When a session opened now expires
+pub fn session_expiry() -> chrono::NaiveDateTime {
+    let duration = chrono::Duration::from_std(*SESSION_DURATION.read().unwrap()).unwrap();
+
+    chrono::Utc::now().naive_utc() + duration
+}
+
+// The token is never given to the client code, only this one-time grant.
+// The client exchanges it at `SESSION_PATH`, for an HttpOnly cookie holding the token
+pub fn grant_session_cookie(token: String) -> String {
+    let grant = new_session_token();
+
+    let mut grants = GRANTS.lock().unwrap();
+
+    grants.retain(|_, (_, granted_at)| granted_at.elapsed() < GRANT_TIMEOUT);
+    grants.insert(grant.clone(), (token, Instant::now()));
+
+    grant
+}
+
+fn take_grant(grant: &str) -> Option<String> {
+    GRANTS
+        .lock()
+        .unwrap()
+        .remove(grant)
+        .filter(|(_, granted_at)| granted_at.elapsed() < GRANT_TIMEOUT)
+        .map(|(token, _)| token)
+}
+
+fn session_cookie(token: &str, max_age: Duration) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        SESSION_COOKIE,
+        token,
+        max_age.as_secs()
+    )
+}
+
+// POST `SESSION_PATH` with a grant as body
+pub(crate) async fn set_session(grant: String) -> Response {
+    match take_grant(grant.trim()) {
+        Some(token) => {
+            let cookie = session_cookie(&token, *SESSION_DURATION.read().unwrap());
+
+            ([(SET_COOKIE, cookie)], StatusCode::NO_CONTENT).into_response()
+        }
+        None => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+// DELETE `SESSION_PATH`, the session itself is closed by the logout RPC
+pub(crate) async fn clear_session() -> Response {
+    let cookie = session_cookie("", Duration::ZERO);
+
+    ([(SET_COOKIE, cookie)], StatusCode::NO_CONTENT).into_response()
+}
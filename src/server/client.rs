@@ -1,32 +1,124 @@
 use axum::extract::ws::{Message, WebSocket};
+use futures::future::{BoxFuture, FutureExt, Shared};
 use futures::SinkExt;
+use lazy_static::lazy_static;
 use tokio::sync::RwLock;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::time::{Duration, Instant};
 
+use axum::http::HeaderMap;
 use futures::stream::SplitSink;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use crate::core::prelude::ProtoTrait;
 
-use super::universe::Universe;
+use super::{context::RequestContext, universe::Universe};
+
+// A response is kept that long, for the client to send its request again after a reconnection
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// The responses kept for one session, the oldest are forgotten first
+const MAX_RESPONSES_PER_SESSION: usize = 256;
+
+type SharedResponse = Shared<BoxFuture<'static, Option<Vec<u8>>>>;
+
+// The RPCs of one session, by request id
+#[derive(Default)]
+struct SessionResponses {
+    user_id: Option<i32>,
+    responses: HashMap<u64, (SharedResponse, Instant)>,
+}
+
+impl SessionResponses {
+    // Make room for a new response: the oldest finished one goes first, or the oldest at all
+    fn make_room(&mut self) {
+        if self.responses.len() < MAX_RESPONSES_PER_SESSION {
+            return;
+        }
+
+        let oldest = self
+            .responses
+            .iter()
+            .min_by_key(|(_, (response, started_at))| (response.peek().is_none(), *started_at))
+            .map(|(request_id, _)| *request_id);
+
+        if let Some(request_id) = oldest {
+            self.responses.remove(&request_id);
+        }
+    }
+}
+
+// By the resume token of the session, that follows it across reconnections
+type Responses = HashMap<String, SessionResponses>;
+
+lazy_static! {
+    // The running and recent RPCs.
+    // A request sent again is not run twice, it waits for the response of the first one
+    static ref RESPONSES: Mutex<Responses> = Mutex::new(HashMap::new());
+}
+
+// Hand the responses of a lost connection over to the one resuming it, for the same user only
+pub fn resume_responses(token: &str, client: &Client) {
+    let mut responses = RESPONSES.lock().unwrap();
+
+    let session = match responses.remove(token) {
+        Some(session) => session,
+        None => return,
+    };
+
+    if session.user_id == client.user_id() {
+        responses.insert(client.resume_token().to_string(), session);
+    }
+}
+
+// Forget the sessions that sent nothing for `RESPONSE_TIMEOUT`
+fn expire_responses(responses: &mut Responses) {
+    responses.retain(|_, session| {
+        session
+            .responses
+            .retain(|_, (_, started_at)| started_at.elapsed() < RESPONSE_TIMEOUT);
+
+        !session.responses.is_empty()
+    });
+}
 
 #[derive(Clone, Debug)]
 pub struct Client {
     out: Arc<RwLock<SplitSink<WebSocket, Message>>>,
     session_id: usize,
+    remote_addr: SocketAddr,
+    headers: Arc<HeaderMap>,
+    user_id: Arc<std::sync::RwLock<Option<i32>>>,
+    session_token: Arc<std::sync::RwLock<Option<String>>>,
+    // Given back by the client when it reconnects, to get its rooms back
+    resume_token: String,
     // universe: Universe,
     // hash: String,
     // db: DatabaseConnection,
 }
 
 impl Client {
-    pub fn new(out: Arc<RwLock<SplitSink<WebSocket, Message>>>, _universe: Universe) -> Self {
+    pub fn new(
+        out: Arc<RwLock<SplitSink<WebSocket, Message>>>,
+        _universe: Universe,
+        remote_addr: SocketAddr,
+        headers: HeaderMap,
+        user_id: Option<i32>,
+        session_token: Option<String>,
+    ) -> Self {
         Self {
             out,
             session_id: 0,
+            remote_addr,
+            headers: Arc::new(headers),
+            user_id: Arc::new(std::sync::RwLock::new(user_id)),
+            session_token: Arc::new(std::sync::RwLock::new(session_token)),
+            resume_token: super::auth::new_session_token(),
             // universe,
             // hash: "".to_string(),
             // db: DatabaseConnection::new(),
@@ -37,38 +129,160 @@ impl Client {
         self.session_id = session_id;
     }
 
-    pub async fn handle_msg<P: ProtoTrait + Send + Serialize + DeserializeOwned + Debug>(&self, msg: Vec<u8>) {
-        let msg = crate::Message::from_bytes(&msg);
-        
-        let proto = P::from_bytes(&msg.msg);
+    pub fn session_id(&self) -> usize {
+        self.session_id
+    }
+
+    pub fn user_id(&self) -> Option<i32> {
+        *self.user_id.read().unwrap()
+    }
+
+    pub fn resume_token(&self) -> &str {
+        &self.resume_token
+    }
+
+    pub fn context(&self, request_id: Option<u64>) -> RequestContext {
+        RequestContext::new(
+            self.session_id,
+            request_id,
+            self.remote_addr,
+            self.headers.clone(),
+            self.user_id.clone(),
+            self.session_token.clone(),
+        )
+    }
 
-        let response = proto.dispatch().await;
+    pub async fn handle_msg<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>(
+        &self,
+        msg: Vec<u8>,
+    ) {
+        // A malformed frame is dropped, the client gets no response for it
+        let msg = match crate::Message::from_bytes(&msg) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
 
-        if let Some(response) = response {
-            let response = response.to_bytes();
+        if !msg.acks.is_empty() {
+            self.forget_responses(&msg.acks);
+        }
 
+        // Nothing else to do for a message that only acknowledges responses
+        if msg.is_push() {
+            return;
+        }
+
+        let proto = match P::from_bytes(&msg.msg) {
+            Ok(proto) => proto,
+            Err(_) => return,
+        };
+
+        let response = {
+            let mut responses = RESPONSES.lock().unwrap();
+
+            expire_responses(&mut responses);
+
+            let session = responses.entry(self.resume_token.clone()).or_default();
+
+            session.user_id = self.user_id();
+
+            if !session.responses.contains_key(&msg.request_id) {
+                session.make_room();
+            }
+
+            let context = self.context(Some(msg.request_id));
+
+            session
+                .responses
+                .entry(msg.request_id)
+                .or_insert_with(|| {
+                    let response = async move {
+                        context
+                            .scope(proto.dispatch())
+                            .await
+                            .map(|response| response.to_bytes())
+                    };
+
+                    (response.boxed().shared(), Instant::now())
+                })
+                .0
+                .clone()
+        };
+
+        if let Some(response) = response.await {
             let msg = crate::Message {
                 request_id: msg.request_id,
                 msg: response,
+                acks: vec![],
             };
 
-            let response = msg.to_bytes();
+            // The client may have disconnected while its RPC was running
+            let _ = self.send(msg).await;
+        }
+    }
+
+    // The client received these responses, they won't be asked again.
+    // The ones still running are kept, a query sent again waits for them
+    fn forget_responses(&self, request_ids: &[u64]) {
+        let mut responses = RESPONSES.lock().unwrap();
 
-            self.out.write().await.send(Message::Binary(response)).await.unwrap();
+        if let Some(session) = responses.get_mut(&self.resume_token) {
+            session.responses.retain(|request_id, (response, _)| {
+                !request_ids.contains(request_id) || response.peek().is_none()
+            });
         }
+    }
 
+    pub async fn send(&self, msg: crate::Message) -> Result<(), axum::Error> {
+        self.out.write().await.send(Message::Binary(msg.to_bytes())).await
     }
 
-    /* pub async fn send<P: Proto + Send + Serialize + DeserializeOwned>(&self, proto: P) {
-        let msg = proto.to_bytes();
-        let msg = crate::Message {request_id: 0, msg};
-        let msg = msg.to_bytes();
-
-        self.out
-            .write()
-            .await
-            .send(Message::Binary(msg))
-            .await
-            .unwrap();
-    } */
+    pub async fn close(&self) {
+        // The socket may already be closed by the client
+        let _ = self.out.write().await.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(done: bool) -> SharedResponse {
+        let response = async { Some(vec![]) }.boxed().shared();
+
+        if done {
+            futures::executor::block_on(response.clone());
+        }
+
+        response
+    }
+
+    #[test]
+    fn finished_responses_make_room_first() {
+        let mut session = SessionResponses::default();
+        let now = Instant::now();
+
+        for request_id in 0..MAX_RESPONSES_PER_SESSION as u64 {
+            // Only the newest one is finished
+            let done = request_id == MAX_RESPONSES_PER_SESSION as u64 - 1;
+
+            session
+                .responses
+                .insert(request_id, (response(done), now + Duration::from_secs(request_id)));
+        }
+
+        session.make_room();
+
+        assert_eq!(session.responses.len(), MAX_RESPONSES_PER_SESSION - 1);
+        assert!(!session
+            .responses
+            .contains_key(&(MAX_RESPONSES_PER_SESSION as u64 - 1)));
+
+        // Without a finished one, the oldest goes
+        session
+            .responses
+            .insert(1000, (response(false), now + Duration::from_secs(1000)));
+        session.make_room();
+
+        assert!(!session.responses.contains_key(&0));
+    }
 }
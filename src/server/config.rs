@@ -0,0 +1,131 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use super::client::Client;
+
+type Hook = Arc<dyn Fn(usize) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+type Authenticator =
+    Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = Option<i32>> + Send>> + Send + Sync>;
+
+// Server side settings, given as the optional second argument of `comet::run!`
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub addr: String,
+    // Delay between two pings sent to each client
+    pub heartbeat_interval: Duration,
+    // A client that didn't send anything (including pongs) for that long is disconnected
+    pub heartbeat_timeout: Duration,
+    // Number of RPCs of a single client that can run at the same time
+    pub max_concurrent_rpcs: usize,
+    // Number of database connections kept open and shared by every query
+    pub db_pool_size: u32,
+    // How long a query waits for a free connection before failing
+    pub db_connection_timeout: Duration,
+    // An unused connection is closed after that long, `None` keeps it open
+    pub db_idle_timeout: Option<Duration>,
+    // How long a session of `comet::auth!` and its cookie last
+    pub session_duration: Duration,
+    on_connect: Vec<Hook>,
+    on_disconnect: Vec<Hook>,
+    authenticator: Option<Authenticator>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:8080".to_string(),
+            heartbeat_interval: Duration::from_secs(30),
+            heartbeat_timeout: Duration::from_secs(90),
+            max_concurrent_rpcs: 16,
+            db_pool_size: 10,
+            db_connection_timeout: Duration::from_secs(30),
+            db_idle_timeout: Some(Duration::from_secs(600)),
+            session_duration: Duration::from_secs(30 * 24 * 3600),
+            on_connect: vec![],
+            on_disconnect: vec![],
+            authenticator: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(mut self, addr: &str) -> Self {
+        self.addr = addr.to_string();
+        self
+    }
+
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    pub fn max_concurrent_rpcs(mut self, max: usize) -> Self {
+        self.max_concurrent_rpcs = max;
+        self
+    }
+
+    pub fn db_pool(mut self, size: u32, connection_timeout: Duration) -> Self {
+        self.db_pool_size = size;
+        self.db_connection_timeout = connection_timeout;
+        self
+    }
+
+    pub fn db_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.db_idle_timeout = timeout;
+        self
+    }
+
+    pub fn session_duration(mut self, duration: Duration) -> Self {
+        self.session_duration = duration;
+        self
+    }
+
+    // Resolves the session cookie of a new client into the id of its user, to authenticate it right away
+    pub fn authenticate<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<i32>> + Send + 'static,
+    {
+        self.authenticator = Some(Arc::new(move |token| Box::pin(f(token))));
+        self
+    }
+
+    pub(crate) async fn authenticated(&self, token: Option<String>) -> Option<i32> {
+        match (&self.authenticator, token) {
+            (Some(authenticator), Some(token)) => authenticator(token).await,
+            _ => None,
+        }
+    }
+
+    // Called with the session id of every new client, before any of its RPC is handled
+    pub fn on_connect<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(usize) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connect.push(Arc::new(move |session_id| Box::pin(f(session_id))));
+        self
+    }
+
+    // Called with the session id of every client that left, once its session is torn down
+    pub fn on_disconnect<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(usize) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_disconnect.push(Arc::new(move |session_id| Box::pin(f(session_id))));
+        self
+    }
+
+    pub(crate) async fn connected(&self, client: &Client) {
+        for hook in &self.on_connect {
+            client.context(None).scope(hook(client.session_id())).await;
+        }
+    }
+
+    pub(crate) async fn disconnected(&self, client: &Client) {
+        for hook in &self.on_disconnect {
+            client.context(None).scope(hook(client.session_id())).await;
+        }
+    }
+}
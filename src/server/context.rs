@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+
+use axum::http::HeaderMap;
+
+tokio::task_local! {
+    static CONTEXT: RequestContext;
+}
+
+// What the server knows about the client whose request is being dispatched.
+// Available from inside `#[rpc]` methods and the session hooks with `request_context()`
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    pub session_id: usize,
+    // None when not called for an RPC, like in the session hooks
+    pub request_id: Option<u64>,
+    pub remote_addr: SocketAddr,
+    // The headers of the HTTP request that was upgraded to the websocket
+    pub headers: Arc<HeaderMap>,
+    // Shared by every request of the session
+    user_id: Arc<RwLock<Option<i32>>>,
+    // The token of the authenticated session, from its cookie or from a login
+    session_token: Arc<RwLock<Option<String>>>,
+}
+
+impl RequestContext {
+    pub fn new(
+        session_id: usize,
+        request_id: Option<u64>,
+        remote_addr: SocketAddr,
+        headers: Arc<HeaderMap>,
+        user_id: Arc<RwLock<Option<i32>>>,
+        session_token: Arc<RwLock<Option<String>>>,
+    ) -> Self {
+        Self {
+            session_id,
+            request_id,
+            remote_addr,
+            headers,
+            user_id,
+            session_token,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    pub fn cookies(&self) -> HashMap<String, String> {
+        parse_cookies(&self.headers)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+
+    // The authenticated user of the session, if any
+    pub fn user_id(&self) -> Option<i32> {
+        *self.user_id.read().unwrap()
+    }
+
+    // Authenticate (or not) the whole session, for this request and the following ones
+    pub fn set_user_id(&self, user_id: Option<i32>) {
+        *self.user_id.write().unwrap() = user_id;
+    }
+
+    pub fn session_token(&self) -> Option<String> {
+        self.session_token.read().unwrap().clone()
+    }
+
+    pub fn set_session_token(&self, token: Option<String>) {
+        *self.session_token.write().unwrap() = token;
+    }
+
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CONTEXT.scope(self, f).await
+    }
+}
+
+pub fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+// The context of the request currently running
+pub fn request_context() -> RequestContext {
+    try_request_context().expect("Not called from within an RPC")
+}
+
+pub fn try_request_context() -> Option<RequestContext> {
+    CONTEXT.try_with(|context| context.clone()).ok()
+}
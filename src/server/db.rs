@@ -0,0 +1,231 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+};
+
+use diesel::{
+    connection::{AnsiTransactionManager, TransactionManager},
+    r2d2::{ConnectionManager, Pool, PooledConnection},
+};
+use lazy_static::lazy_static;
+
+use super::config::ServerConfig;
+use crate::core::prelude::DbError;
+
+// The database the models are stored in, chosen with the `sqlite` feature
+#[cfg(not(feature = "sqlite"))]
+pub type DbBackend = diesel::pg::Pg;
+#[cfg(not(feature = "sqlite"))]
+pub type DbRawConnection = diesel::pg::PgConnection;
+
+#[cfg(feature = "sqlite")]
+pub type DbBackend = diesel::sqlite::Sqlite;
+#[cfg(feature = "sqlite")]
+pub type DbRawConnection = diesel::sqlite::SqliteConnection;
+
+pub type DbPool = Pool<ConnectionManager<DbRawConnection>>;
+pub type DbConnection = PooledConnection<ConnectionManager<DbRawConnection>>;
+
+// The connection of a transaction, and what to do once it is committed
+struct Transaction {
+    conn: Mutex<DbConnection>,
+    on_commit: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+// Dropped before its end, by a panic or a cancelled future, the transaction is still open.
+// Its connection is marked broken, for the pool to close it (rolling it back) instead of reusing it
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let conn = self.conn.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let status = AnsiTransactionManager::transaction_manager_status_mut(&mut **conn);
+
+        if !matches!(status.transaction_depth(), Ok(None)) {
+            status.set_in_error();
+        }
+    }
+}
+
+type SharedTransaction = Arc<Transaction>;
+
+lazy_static! {
+    // Created by `server::run`, or with the default settings on first use
+    static ref POOL: RwLock<Option<DbPool>> = RwLock::new(None);
+}
+
+tokio::task_local! {
+    // The connection of the transaction the current task is in, if any
+    static TRANSACTION: SharedTransaction;
+}
+
+fn build_pool(config: &ServerConfig) -> DbPool {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    let builder = Pool::builder()
+        .max_size(config.db_pool_size)
+        .connection_timeout(config.db_connection_timeout)
+        .idle_timeout(config.db_idle_timeout);
+
+    #[cfg(feature = "sqlite")]
+    let builder = builder.connection_customizer(Box::new(SqlitePragmas {
+        busy_timeout: config.db_connection_timeout,
+    }));
+
+    // The connections are opened on demand, an unreachable database fails the queries, not the server
+    builder.build_unchecked(ConnectionManager::new(database_url))
+}
+
+// SQLite doesn't check the foreign keys unless asked, and fails right away on a database locked by another write
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqlitePragmas {
+    busy_timeout: std::time::Duration,
+}
+
+#[cfg(feature = "sqlite")]
+impl diesel::r2d2::CustomizeConnection<DbRawConnection, diesel::r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, conn: &mut DbRawConnection) -> Result<(), diesel::r2d2::Error> {
+        use diesel::connection::SimpleConnection;
+
+        conn.batch_execute(&format!(
+            "PRAGMA foreign_keys = ON; PRAGMA busy_timeout = {};",
+            self.busy_timeout.as_millis()
+        ))
+        .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+pub(crate) fn init_pool(config: &ServerConfig) {
+    POOL.write().unwrap().replace(build_pool(config));
+}
+
+pub fn pool() -> DbPool {
+    if let Some(pool) = POOL.read().unwrap().as_ref() {
+        return pool.clone();
+    }
+
+    POOL.write()
+        .unwrap()
+        .get_or_insert_with(|| build_pool(&ServerConfig::default()))
+        .clone()
+}
+
+// Waits for a free connection, up to the connection timeout. Blocks the current thread
+pub fn connection() -> Result<DbConnection, DbError> {
+    Ok(pool().get()?)
+}
+
+fn current_transaction() -> Option<SharedTransaction> {
+    TRANSACTION.try_with(Clone::clone).ok()
+}
+
+// A query that panicked in the transaction leaves it unusable
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, DbError> {
+    mutex
+        .lock()
+        .map_err(|_| DbError::Other("Transaction aborted by a panic".to_string()))
+}
+
+// Runs `f` on the connection of the current transaction, or on a free one of the pool
+fn run<F, T>(transaction: Option<SharedTransaction>, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut DbRawConnection) -> Result<T, DbError>,
+{
+    match transaction {
+        Some(transaction) => f(&mut **lock(&transaction.conn)?),
+        None => f(&mut *connection()?),
+    }
+}
+
+// Runs `f` once the current transaction is committed, or right away outside of one.
+// Nothing is run when it is rolled back
+#[cfg(feature = "sqlite")]
+pub(crate) fn after_commit<F: FnOnce() + Send + 'static>(f: F) {
+    match current_transaction() {
+        Some(transaction) => {
+            if let Ok(mut on_commit) = transaction.on_commit.lock() {
+                on_commit.push(Box::new(f));
+            }
+        }
+        None => f(),
+    }
+}
+
+// Runs the queries on a thread made for blocking calls, out of the way of the other tasks
+pub async fn with_connection<F, T>(f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut DbRawConnection) -> Result<T, DbError> + Send + 'static,
+    T: Send + 'static,
+{
+    let transaction = current_transaction();
+
+    tokio::task::spawn_blocking(move || run(transaction, f))
+        .await
+        .map_err(|e| DbError::Other(e.to_string()))?
+}
+
+// Same, for the synchronous code that can't move its queries to another thread.
+// The other tasks of the current thread are handed to the rest of the runtime meanwhile
+pub fn with_connection_blocking<F, T>(f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut DbRawConnection) -> Result<T, DbError>,
+{
+    let transaction = current_transaction();
+
+    tokio::task::block_in_place(move || run(transaction, f))
+}
+
+// Runs every model method and query awaited by `f` on the same connection, in a single transaction.
+// It is committed when `f` returns `Ok`, and rolled back when it returns `Err`.
+// A transaction started inside another one is part of it
+pub async fn transaction<F, Fut, T, E>(f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: From<DbError>,
+{
+    if current_transaction().is_some() {
+        return f().await;
+    }
+
+    let transaction = tokio::task::spawn_blocking(|| -> Result<SharedTransaction, DbError> {
+        let mut conn = connection()?;
+
+        AnsiTransactionManager::begin_transaction(&mut *conn)?;
+
+        Ok(Arc::new(Transaction {
+            conn: Mutex::new(conn),
+            on_commit: Mutex::new(vec![]),
+        }))
+    })
+    .await
+    .map_err(|e| DbError::Other(e.to_string()))??;
+
+    let res = TRANSACTION
+        .scope(transaction.clone(), async { f().await })
+        .await;
+    let commit = res.is_ok();
+
+    let end = tokio::task::spawn_blocking(move || -> Result<(), DbError> {
+        let mut conn = lock(&transaction.conn)?;
+
+        if commit {
+            AnsiTransactionManager::commit_transaction(&mut **conn)?;
+
+            for f in lock(&transaction.on_commit)?.drain(..) {
+                f();
+            }
+        } else {
+            AnsiTransactionManager::rollback_transaction(&mut **conn)?;
+        }
+
+        Ok(())
+    })
+    .await
+    .map_err(|e| DbError::Other(e.to_string()))?;
+
+    // A failed rollback discards the connection, the error of `f` is the one that matters
+    match res {
+        Ok(value) => end.map(|_| value).map_err(E::from),
+        Err(e) => Err(e),
+    }
+}
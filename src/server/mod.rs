@@ -1,5 +1,12 @@
 mod app;
+pub mod auth;
 mod client;
+pub mod config;
+pub mod context;
+pub mod db;
+pub mod policy;
 pub mod prelude;
+pub mod rooms;
 pub mod server;
 mod universe;
+pub mod watch;
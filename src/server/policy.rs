@@ -0,0 +1,30 @@
+use async_trait::async_trait;
+
+use super::context::RequestContext;
+
+// Authorization rules of the generated endpoints of a model, given with `#[model(policy = MyPolicy)]`.
+// Every rule allows everything by default. `K` is the primary key of the model.
+// `update` and `delete` are judged on the row stored in the database, and are refused when there is no such row.
+// `update` also gets the model sent by the client, that replaces the stored one when allowed
+#[async_trait]
+pub trait Policy<M: Sync, K: Sync = i32> {
+    async fn can_create(_context: &RequestContext, _model: &M) -> bool {
+        true
+    }
+
+    async fn can_list(_context: &RequestContext) -> bool {
+        true
+    }
+
+    async fn can_fetch(_context: &RequestContext, _id: &K) -> bool {
+        true
+    }
+
+    async fn can_update(_context: &RequestContext, _id: &K, _stored: &M, _sent: &M) -> bool {
+        true
+    }
+
+    async fn can_delete(_context: &RequestContext, _id: &K, _stored: &M) -> bool {
+        true
+    }
+}
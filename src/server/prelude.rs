@@ -1,3 +1,18 @@
 pub use tokio;
 
 pub use tokio::task::spawn_local;
+
+pub use crate::server::auth::{
+    grant_session_cookie, hash_password, new_session_token, session_expiry, verify_password,
+};
+pub use crate::server::config::ServerConfig;
+pub use crate::server::context::{request_context, try_request_context, RequestContext};
+pub use crate::server::db::{
+    connection, transaction, with_connection, with_connection_blocking, DbBackend, DbConnection,
+    DbPool, DbRawConnection,
+};
+pub use crate::server::policy::Policy;
+pub use crate::server::rooms::{broadcast, current_session_id, join_room, leave_room};
+#[cfg(feature = "sqlite")]
+pub use crate::server::watch::table_changed;
+pub use crate::server::watch::{query_hash, unwatch, watch};
@@ -0,0 +1,30 @@
+use serde::Serialize;
+
+use crate::core::prelude::{Push, RoomEvent};
+
+use super::context::request_context;
+use super::universe::{send_to, UNIVERSE};
+
+// The session id of the client that issued the RPC currently running
+pub fn current_session_id() -> usize {
+    request_context().session_id
+}
+
+// Make the calling client join the given room
+pub async fn join_room(room: &str) {
+    UNIVERSE.write().await.join(room, current_session_id());
+}
+
+// Make the calling client leave the given room
+pub async fn leave_room(room: &str) {
+    UNIVERSE.write().await.leave(room, current_session_id());
+}
+
+// Send the payload to every client that joined the room
+pub async fn broadcast<T: Serialize>(room: &str, payload: &T) {
+    let msg = crate::Message::push(Push::Room(RoomEvent::new(room, payload)).to_bytes());
+
+    let members = UNIVERSE.read().await.room_sessions(room);
+
+    send_to(&members, msg).await;
+}
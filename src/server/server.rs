@@ -1,68 +1,182 @@
-use std::sync::Arc;
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::Instant;
 
 use axum::{
-    extract::ws::{WebSocket, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Query,
+    },
+    http::HeaderMap,
     response::Response,
-    routing::get,
+    routing::{get, post},
     Extension, Router,
 };
 use axum_extra::routing::SpaRouter;
 
-use crate::core::prelude::ProtoTrait;
+use crate::core::prelude::{ProtoTrait, Push, SESSION_COOKIE, SESSION_PATH};
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use super::{client::Client, universe::Universe};
+use super::{
+    auth,
+    client::{resume_responses, Client},
+    config::ServerConfig,
+    context::parse_cookies,
+    db,
+    universe::{Universe, UNIVERSE},
+    watch,
+};
 
-use futures::stream::StreamExt;
+use futures::{stream::StreamExt, SinkExt};
 
 async fn handler<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Query(mut params): Query<HashMap<String, String>>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
     Extension(universe): Extension<Universe>,
+    Extension(config): Extension<Arc<ServerConfig>>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_socket::<P>(socket, universe))
+    // Given back by a client reconnecting
+    let resume = params.remove("resume");
+
+    ws.on_upgrade(move |socket| {
+        handle_socket::<P>(socket, universe, config, remote_addr, headers, resume)
+    })
 }
 
-async fn handle_socket<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>(socket: WebSocket, universe: Universe) {
+async fn handle_socket<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>(
+    socket: WebSocket,
+    universe: Universe,
+    config: Arc<ServerConfig>,
+    remote_addr: SocketAddr,
+    headers: HeaderMap,
+    resume: Option<String>,
+) {
+    let token = parse_cookies(&headers).remove(SESSION_COOKIE);
+    let user_id = config.authenticated(token.clone()).await;
+
+    // The token of a session that didn't resolve is of no use
+    let token = token.filter(|_| user_id.is_some());
+
     let (tx, mut rx) = socket.split();
 
     let tx = Arc::new(RwLock::new(tx));
 
-    let session_id = universe
-        .write()
-        .await
-        .new_client(Client::new(tx.clone(), universe.clone()));
+    let session_id = universe.write().await.new_client(Client::new(
+        tx.clone(),
+        universe.clone(),
+        remote_addr,
+        headers,
+        user_id,
+        token,
+    ));
 
-    while let Some(msg) = rx.next().await {
-        let msg = if let Ok(msg) = msg {
-            msg
-        } else {
-            // client disconnected
-            return;
-        };
+    let client = universe.read().await.get_client(session_id);
 
-        let client = universe.read().await.get_client(session_id);
+    if let Some(resume) = resume {
+        universe.write().await.resume(&resume, session_id);
 
-        client.handle_msg::<P>(msg.into()).await;
+        // The queries sent again are answered from the responses of the lost connection
+        resume_responses(&resume, &client);
     }
+
+    let resume_token = Push::Resume(client.resume_token().to_string());
+
+    // The client may be gone already, the loop below sees it
+    let _ = client
+        .send(crate::Message::push(resume_token.to_bytes()))
+        .await;
+
+    config.connected(&client).await;
+
+    // Limits the number of RPCs of this client running concurrently
+    let rpc_slots = Arc::new(Semaphore::new(config.max_concurrent_rpcs));
+
+    let mut heartbeat = tokio::time::interval(config.heartbeat_interval);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            msg = rx.next() => {
+                let msg = if let Some(Ok(msg)) = msg {
+                    msg
+                } else {
+                    // client disconnected
+                    break;
+                };
+
+                last_seen = Instant::now();
+
+                match msg {
+                    Message::Binary(msg) => {
+                        let client = client.clone();
+
+                        // Waiting for a free slot stops reading from the socket until one RPC is done
+                        let slot = rpc_slots.clone().acquire_owned().await.unwrap();
+
+                        // Each response carries the request_id of its query, so they can be sent in any order
+                        tokio::spawn(async move {
+                            client.handle_msg::<P>(msg).await;
+
+                            drop(slot);
+                        });
+                    }
+                    Message::Close(_) => break,
+                    // Pings are answered by axum, and pongs only keep the session alive
+                    _ => (),
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > config.heartbeat_timeout {
+                    // dead connection
+                    break;
+                }
+
+                if tx.write().await.send(Message::Ping(vec![])).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    universe.write().await.remove_client(session_id);
+
+    watch::unwatch_all(session_id).await;
+
+    client.close().await;
+
+    config.disconnected(&client).await;
 }
 
-pub async fn run<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>() {
+pub async fn run<P: ProtoTrait + Send + 'static + Serialize + DeserializeOwned + Debug>(
+    config: ServerConfig,
+) {
+    let addr = config.addr.clone();
+
+    db::init_pool(&config);
+    auth::init(&config);
+
     let app = Router::new()
         .route("/ws", get(handler::<P>))
-        .layer(Extension(Universe::default()))
+        .route(SESSION_PATH, post(auth::set_session).delete(auth::clear_session))
+        .layer(Extension(UNIVERSE.clone()))
+        .layer(Extension(Arc::new(config)))
         .merge(SpaRouter::new("/assets", "dist"));
 
-    let addr = "0.0.0.0:8080";
+    #[cfg(not(feature = "sqlite"))]
+    tokio::spawn(watch::listen());
 
     println!(" -> Listening on {}", addr);
 
     axum::Server::bind(&addr.parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
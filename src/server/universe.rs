@@ -1,14 +1,37 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::RwLock;
 
+use lazy_static::lazy_static;
+
 use super::client::Client;
 
 pub type Universe = Arc<RwLock<UniverseInner>>;
 
+lazy_static! {
+    pub static ref UNIVERSE: Universe = Universe::default();
+}
+
+// The rooms of a lost connection are kept that long, for the client to reconnect
+const RESUME_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The rooms of a lost connection, and its user
+#[derive(Clone, Debug)]
+struct Suspended {
+    rooms: Vec<String>,
+    user_id: Option<i32>,
+    suspended_at: Instant,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct UniverseInner {
     next_session_id: usize,
-    clients: HashMap<usize, Client>, // session_id -> Client
+    clients: HashMap<usize, Client>,          // session_id -> Client
+    rooms: HashMap<String, HashSet<usize>>, // room name -> session_ids
+    suspended: HashMap<String, Suspended>,  // resume token -> rooms of the lost connection
 }
 
 impl UniverseInner {
@@ -33,4 +56,112 @@ impl UniverseInner {
     pub fn get_client(&self, session_id: usize) -> Client {
         self.clients.get(&session_id).unwrap().clone()
     }
+
+    // The client may have left already
+    pub fn try_get_client(&self, session_id: usize) -> Option<Client> {
+        self.clients.get(&session_id).cloned()
+    }
+
+    // Tear down the session: the client is forgotten and leaves all its rooms.
+    // Its rooms are kept a while under its resume token
+    pub fn remove_client(&mut self, session_id: usize) -> Option<Client> {
+        if let Some(client) = self.clients.get(&session_id) {
+            let rooms = self
+                .rooms
+                .iter()
+                .filter(|(_, members)| members.contains(&session_id))
+                .map(|(room, _)| room.clone())
+                .collect::<Vec<_>>();
+
+            if !rooms.is_empty() {
+                let suspended = Suspended {
+                    rooms,
+                    user_id: client.user_id(),
+                    suspended_at: Instant::now(),
+                };
+
+                self.suspended
+                    .insert(client.resume_token().to_string(), suspended);
+            }
+        }
+
+        self.leave_all(session_id);
+
+        self.clients.remove(&session_id)
+    }
+
+    // Join the rooms of the lost connection of this token again.
+    // Only for the same user, and not after `RESUME_TIMEOUT`
+    pub fn resume(&mut self, token: &str, session_id: usize) {
+        self.suspended
+            .retain(|_, suspended| suspended.suspended_at.elapsed() < RESUME_TIMEOUT);
+
+        let suspended = match self.suspended.remove(token) {
+            Some(suspended) => suspended,
+            None => return,
+        };
+
+        let user_id = match self.clients.get(&session_id) {
+            Some(client) => client.user_id(),
+            None => return,
+        };
+
+        if suspended.user_id != user_id {
+            return;
+        }
+
+        for room in suspended.rooms {
+            self.join(&room, session_id);
+        }
+    }
+
+    pub fn join(&mut self, room: &str, session_id: usize) {
+        self.rooms
+            .entry(room.to_string())
+            .or_default()
+            .insert(session_id);
+    }
+
+    pub fn leave(&mut self, room: &str, session_id: usize) {
+        if let Some(members) = self.rooms.get_mut(room) {
+            members.remove(&session_id);
+
+            if members.is_empty() {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    // Remove the session from every room it joined
+    pub fn leave_all(&mut self, session_id: usize) {
+        self.rooms.retain(|_, members| {
+            members.remove(&session_id);
+
+            !members.is_empty()
+        });
+    }
+
+    pub fn room_sessions(&self, room: &str) -> Vec<usize> {
+        self.rooms
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Push the message to the clients of these sessions that are still connected.
+// A failing client is about to be disconnected, it doesn't stop the others
+pub async fn send_to(sessions: &[usize], msg: crate::Message) {
+    let clients = {
+        let universe = UNIVERSE.read().await;
+
+        sessions
+            .iter()
+            .filter_map(|session_id| universe.try_get_client(*session_id))
+            .collect::<Vec<_>>()
+    };
+
+    for client in clients {
+        let _ = client.send(msg.clone()).await;
+    }
 }
@@ -0,0 +1,320 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    future::Future,
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::Arc,
+};
+
+#[cfg(not(feature = "sqlite"))]
+use futures::StreamExt;
+#[cfg(not(feature = "sqlite"))]
+use std::collections::HashSet;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock};
+#[cfg(not(feature = "sqlite"))]
+use tokio_postgres::{AsyncMessage, NoTls};
+
+use crate::core::prelude::{DbError, ModelKey, Push, QueryHash, WatchEvent};
+
+use super::context::{request_context, RequestContext};
+use super::universe::send_to;
+
+// Channel notified by the triggers generated along with the tables, with the name of the changed table
+pub const NOTIFY_CHANNEL: &str = "comet_changes";
+
+// Waited before listening again to the database, after losing the connection
+#[cfg(not(feature = "sqlite"))]
+const RECONNECT_DELAY: std::time::Duration = std::time::Duration::from_secs(5);
+
+type Rows = Vec<(ModelKey, Vec<u8>)>;
+type WatchQuery =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Result<Rows, DbError>> + Send>> + Send + Sync>;
+
+// A session watching a query. Its results can depend on the user,
+// the query runs again for each session in its own context
+struct Watcher {
+    context: RequestContext,
+    // The results last sent to the session
+    last: Rows,
+    // One refresh at a time, for the changes to reach the session in order
+    refreshing: Arc<Mutex<()>>,
+}
+
+struct Watch {
+    table: String,
+    query: WatchQuery,
+    watchers: HashMap<usize, Watcher>,
+}
+
+lazy_static! {
+    static ref WATCHES: RwLock<HashMap<QueryHash, Watch>> = RwLock::new(HashMap::new());
+}
+
+pub fn query_hash<A: Serialize>(name: &str, args: &A) -> QueryHash {
+    let mut hasher = DefaultHasher::new();
+
+    name.hash(&mut hasher);
+    serde_cbor::to_vec(args).unwrap().hash(&mut hasher);
+
+    hasher.finish()
+}
+
+fn serialize<T: Serialize>(rows: &[(ModelKey, T)]) -> Rows {
+    rows.iter()
+        .map(|(id, model)| (id.clone(), serde_cbor::to_vec(model).unwrap()))
+        .collect()
+}
+
+// Run the query and subscribe the calling client to the changes of its results.
+// The query is run again every time the given table changes.
+pub async fn watch<T, F, Fut>(
+    query_hash: QueryHash,
+    table: &str,
+    query: F,
+) -> Result<Vec<(ModelKey, T)>, DbError>
+where
+    T: Serialize + Send + 'static,
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<(ModelKey, T)>, DbError>> + Send + 'static,
+{
+    let rows = query().await?;
+    let last = serialize(&rows);
+
+    let query: WatchQuery = Arc::new(move || {
+        let results = query();
+
+        Box::pin(async move { Ok(serialize(&results.await?)) })
+    });
+
+    // The refreshes are not part of the request that started the watch
+    let mut context = request_context();
+    context.request_id = None;
+
+    WATCHES
+        .write()
+        .await
+        .entry(query_hash)
+        .or_insert_with(|| Watch {
+            table: table.to_string(),
+            query,
+            watchers: HashMap::new(),
+        })
+        .watchers
+        .insert(
+            context.session_id,
+            Watcher {
+                context,
+                last,
+                refreshing: Arc::new(Mutex::new(())),
+            },
+        );
+
+    Ok(rows)
+}
+
+// Unsubscribe the calling client from a watched query
+pub async fn unwatch(query_hash: QueryHash) {
+    let session_id = request_context().session_id;
+
+    let mut watches = WATCHES.write().await;
+
+    if let Some(watch) = watches.get_mut(&query_hash) {
+        watch.watchers.remove(&session_id);
+
+        if watch.watchers.is_empty() {
+            watches.remove(&query_hash);
+        }
+    }
+}
+
+// Forget every watch of a session that left
+pub async fn unwatch_all(session_id: usize) {
+    WATCHES.write().await.retain(|_, watch| {
+        watch.watchers.remove(&session_id);
+
+        !watch.watchers.is_empty()
+    });
+}
+
+fn diff(query_hash: QueryHash, last: &Rows, rows: &Rows) -> Option<WatchEvent> {
+    if last == rows {
+        return None;
+    }
+
+    let previous = last.iter().cloned().collect::<HashMap<_, _>>();
+    let order = rows.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
+
+    let models = rows
+        .iter()
+        .filter(|(id, model)| previous.get(id) != Some(model))
+        .cloned()
+        .collect();
+
+    let deleted_ids = last
+        .iter()
+        .map(|(id, _)| id.clone())
+        .filter(|id| !order.contains(id))
+        .collect();
+
+    Some(WatchEvent {
+        query_hash,
+        models,
+        deleted_ids,
+        order,
+    })
+}
+
+// Run again the queries watching the table, each in its own task so that a failing one doesn't stop the others
+async fn refresh(table: &str) {
+    let watchers = WATCHES
+        .read()
+        .await
+        .iter()
+        .filter(|(_, watch)| watch.table == table)
+        .flat_map(|(query_hash, watch)| {
+            watch.watchers.iter().map(move |(session_id, watcher)| {
+                (
+                    *query_hash,
+                    *session_id,
+                    watch.query.clone(),
+                    watcher.context.clone(),
+                    watcher.refreshing.clone(),
+                )
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (query_hash, session_id, query, context, refreshing) in watchers {
+        tokio::spawn(async move {
+            let _refreshing = refreshing.lock().await;
+
+            refresh_watcher(query_hash, session_id, query, context).await;
+        });
+    }
+}
+
+// Run the query in the context of the session, and push it the changes of the results
+async fn refresh_watcher(query_hash: QueryHash, session_id: usize, query: WatchQuery, context: RequestContext) {
+    let rows = match context.scope(query()).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!(" -> Cannot refresh a watched query: {}", e);
+
+            return;
+        }
+    };
+
+    let event = {
+        let mut watches = WATCHES.write().await;
+
+        // The session may have left while the query was running
+        let watcher = match watches
+            .get_mut(&query_hash)
+            .and_then(|watch| watch.watchers.get_mut(&session_id))
+        {
+            Some(watcher) => watcher,
+            None => return,
+        };
+
+        let event = diff(query_hash, &watcher.last, &rows);
+
+        watcher.last = rows;
+
+        event
+    };
+
+    if let Some(event) = event {
+        let msg = crate::Message::push(Push::Event(event).to_bytes());
+
+        send_to(&[session_id], msg).await;
+    }
+}
+
+// SQLite can't notify the server, the model methods tell it about their writes instead.
+// The watched queries are not refreshed by the writes made another way
+#[cfg(feature = "sqlite")]
+pub fn table_changed(table: &str) {
+    let table = table.to_string();
+
+    super::db::after_commit(move || {
+        tokio::spawn(async move { refresh(&table).await });
+    });
+}
+
+// Listen to the notifications of the database triggers for as long as the server runs.
+// A lost connection is opened again, and every watched query is refreshed for the changes missed meanwhile
+#[cfg(not(feature = "sqlite"))]
+pub async fn listen() {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+    let mut reconnecting = false;
+
+    loop {
+        match listen_once(&database_url, reconnecting).await {
+            Ok(()) => println!(" -> Lost the connection listening to the database changes"),
+            Err(e) => println!(" -> Cannot listen to the database changes: {}", e),
+        }
+
+        reconnecting = true;
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// Returns once the connection is closed
+#[cfg(not(feature = "sqlite"))]
+async fn listen_once(database_url: &str, reconnecting: bool) -> Result<(), tokio_postgres::Error> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // The connection must be polled for the client to work at all
+    tokio::spawn(async move {
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    let _ = tx.send(Ok(notification.payload().to_string()));
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+
+                    break;
+                }
+            }
+        }
+    });
+
+    client
+        .batch_execute(&format!("LISTEN {}", NOTIFY_CHANNEL))
+        .await?;
+
+    if reconnecting {
+        refresh_all().await;
+    }
+
+    // The queries run in their own tasks, the notifications keep coming meanwhile
+    while let Some(table) = rx.recv().await {
+        refresh(&table?).await;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn refresh_all() {
+    let tables = WATCHES
+        .read()
+        .await
+        .values()
+        .map(|watch| watch.table.clone())
+        .collect::<HashSet<_>>();
+
+    for table in tables {
+        refresh(&table).await;
+    }
+}
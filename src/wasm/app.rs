@@ -1,5 +1,10 @@
 use crate::prelude::*;
 
+thread_local! {
+    // Renders the whole tree again from the root component
+    static REDRAW: RefCell<Option<Rc<dyn Fn()>>> = RefCell::new(None);
+}
+
 impl<Comp, Msg> App<Comp, Msg>
 where
     Comp: Component<Msg> + 'static,
@@ -8,8 +13,31 @@ where
     pub async fn run(&mut self) {
         let window = web_sys::window().expect("no global `window` exists");
         let document = window.document().expect("should have a document on window");
-        let body = document.body().expect("document should have a body");
+        let body: web_sys::Element = document.body().expect("document should have a body").into();
+
+        let root = self.root.clone();
+        let body2 = body.clone();
+
+        REDRAW.with(|redraw| {
+            *redraw.borrow_mut() = Some(Rc::new(move || {
+                let root = root.clone();
+                let body = body2.clone();
+
+                spawn_local(async move {
+                    crate::core::component::run_rec(root, &body).await;
+                });
+            }));
+        });
+
+        crate::core::component::run_rec(self.root.clone(), &body).await;
+    }
+}
+
+// Trigger a render of the whole application, for changes that don't come from a DOM event
+pub fn redraw() {
+    let redraw = REDRAW.with(|redraw| redraw.borrow().clone());
 
-        crate::core::component::run_rec(self.root.clone(), &body.into()).await;
+    if let Some(redraw) = redraw {
+        redraw();
     }
 }
@@ -0,0 +1,42 @@
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::prelude::*;
+
+// The session cookie is HttpOnly: the client never sees the token, it only asks the server
+// to set or clear the cookie, that is then sent with the websocket upgrade request of the next visits
+async fn session_request(method: &str, body: Option<&str>) -> Result<(), String> {
+    let init = web_sys::RequestInit::new();
+
+    init.set_method(method);
+
+    if let Some(body) = body {
+        init.set_body(&JsValue::from_str(body));
+    }
+
+    let request = web_sys::Request::new_with_str_and_init(SESSION_PATH, &init)
+        .map_err(|e| format!("Cannot build the session request: {:?}", e))?;
+
+    let response = JsFuture::from(web_sys::window().unwrap().fetch_with_request(&request))
+        .await
+        .map_err(|e| format!("Cannot reach the server: {:?}", e))?;
+
+    let response: web_sys::Response = response.dyn_into().unwrap();
+
+    if response.ok() {
+        Ok(())
+    } else {
+        Err(format!("Session refused by the server: {}", response.status()))
+    }
+}
+
+// Exchanges the grant given by `auth_register` or `auth_login` for the session cookie
+pub async fn store_session(grant: &str) -> Result<(), String> {
+    session_request("POST", Some(grant)).await
+}
+
+pub async fn clear_session() {
+    if let Err(e) = session_request("DELETE", None).await {
+        console_log!("{}", e);
+    }
+}
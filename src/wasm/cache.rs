@@ -1,8 +1,15 @@
 use std::{
     collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    future::Future,
     hash::{Hash, Hasher},
+    thread::LocalKey,
+    time::Duration,
 };
 
+use futures::future::{FutureExt, LocalBoxFuture, Shared as SharedFuture};
+
+use crate::prelude::*;
+
 // client check local cache for the QueryHash
 // if query exists with given params
 //   register the componentId with the QueryHash in the ComponentQueryBinder
@@ -30,13 +37,13 @@ pub type QueryId = u64;
 pub type ComponentId = String;
 
 // The message that is sent to the server and back
-pub enum Message {
+pub enum Message<M> {
     Query(Query),
-    Response(Vec<Model>),
-    Event(QueryHash, Vec<Model>, Vec<i32>), // query Hash, new/update models, deleted ids
+    Response(QueryHash, Vec<M>),
+    Event(QueryHash, Vec<M>, Vec<ModelKey>), // query Hash, new/update models, deleted keys
 }
 
-#[derive(Hash)]
+#[derive(Hash, Clone, Debug)]
 pub struct Query {
     query_id: QueryId,
     params: Vec<String>,
@@ -44,6 +51,14 @@ pub struct Query {
 }
 
 impl Query {
+    pub fn new(query_id: QueryId, params: Vec<String>, model_id: ModelId) -> Self {
+        Self {
+            query_id,
+            params,
+            model_id,
+        }
+    }
+
     pub fn calc_hash(&self) -> QueryHash {
         let mut hasher = DefaultHasher::new();
         self.hash(&mut hasher);
@@ -51,77 +66,228 @@ impl Query {
     }
 }
 
-pub type QueryHash = u64;
+pub type ModelId = u64;
 
-pub struct Cache {
-    query: QueryCache,
-    component_query_binder: ComponentQueryBinder,
-    pub models: ModelCache,
+// Implemented by the `Model` enum generated with a variant for every `#[model]`
+pub trait CacheModel: Clone + 'static {
+    fn id(&self) -> ModelKey;
+    fn model_id(&self) -> ModelId;
 }
 
-pub type ModelId = u64;
+type Pending<M> = SharedFuture<LocalBoxFuture<'static, Result<Vec<M>, DbError>>>;
+
+// How long an answered query is served from the cache, by default
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
 
-// generate_model_enum!();
-#[derive(Debug, Clone)]
-pub enum Model {
-    // Model::User(User),
+// The cache sees the writes of this client right away, and the writes of the other clients:
+// - as soon as the server pushes them, for the models of a `#[watch]` query of this client
+// - otherwise once the cached query is older than the TTL, and asked again
+pub struct Cache<M: CacheModel> {
+    query: QueryCache,
+    component_query_binder: ComponentQueryBinder,
+    pub models: ModelCache<M>,
+    // The queries sent to the server that didn't answer yet, with the generation of their model when sent
+    pending: BTreeMap<QueryHash, (u64, Pending<M>)>,
+    // Bumped by every invalidation of the queries of a model, by model id.
+    // An answer to a query sent before is not cached, it may have missed the change
+    generations: BTreeMap<ModelId, u64>,
+    // `None` keeps the answers until they are invalidated
+    ttl: Option<Duration>,
 }
 
-impl Model {
-    pub fn id(&self) -> i32 {
-        match self {
-            // Model::User(user) => user.id,
-            _ => 0,
-        }
+impl<M: CacheModel> Default for Cache<M> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 // client check local cache for the queryId
-impl Cache {
+impl<M: CacheModel> Cache<M> {
+    pub fn new() -> Self {
+        Self {
+            query: QueryCache::default(),
+            component_query_binder: ComponentQueryBinder::default(),
+            models: ModelCache::default(),
+            pending: BTreeMap::new(),
+            generations: BTreeMap::new(),
+            ttl: Some(DEFAULT_TTL),
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
     // returns the component_ids that needs to be redrawn
-    pub async fn handle_message(&mut self, message: Message) -> Option<BTreeSet<ComponentId>> {
+    pub fn handle_message(&mut self, message: Message<M>) -> Option<BTreeSet<ComponentId>> {
         match message {
+            // Sent by the client, there is no result to store yet
+            Message::Query(query) => {
+                self.query.insert_query(query);
+
+                None
+            }
+            Message::Response(query_hash, models) => {
+                self.query
+                    .set_ids(query_hash, models.iter().map(|model| model.id()).collect());
+                self.models.update(models);
+
+                self.component_query_binder
+                    .get_components(query_hash)
+                    .cloned()
+            }
             Message::Event(query_hash, models, deleted_ids) => {
-                self.models.update(query_hash, models.clone(), deleted_ids);
+                self.query.remove_ids(query_hash, &deleted_ids);
                 self.query
                     .insert_ids(query_hash, models.iter().map(|model| model.id()).collect());
 
+                if let Some(query) = self.query.get_query(&query_hash) {
+                    let model_id = query.model_id;
+
+                    self.models.remove(model_id, deleted_ids);
+                }
+
+                self.models.update(models);
+
                 self.component_query_binder
                     .get_components(query_hash)
                     .cloned()
             }
-            _ => unimplemented!(),
         }
     }
 
-    pub async fn query(
-        &mut self,
-        query: Query,
-        component_id: ComponentId,
-    ) -> Result<Vec<Model>, ()> {
-        let query_hash = query.calc_hash();
+    pub fn bind(&mut self, query_hash: QueryHash, component_id: ComponentId) {
+        self.component_query_binder.bind(query_hash, component_id);
+    }
+
+    // The cached results of the query, if it has been answered already and is not expired
+    pub fn get(&self, query_hash: &QueryHash) -> Option<Vec<M>> {
+        if let Some(ttl) = self.ttl {
+            if self.query.age(query_hash)? > ttl {
+                return None;
+            }
+        }
 
-        if self.query.contains(&query_hash) {
-            self.component_query_binder.bind(query_hash, component_id);
+        let query = self.query.get_query(query_hash)?;
+        let ids = self.query.get_query_ids(query_hash)?;
 
-            return self.models.get(query.model_id).ok_or(());
-        } else {
-            self.query.insert_query(query);
+        Some(self.models.get_ids(query.model_id, ids))
+    }
 
-            self.component_query_binder.bind(query_hash, component_id);
+    pub fn model(&self, model_id: ModelId, id: ModelKey) -> Option<M> {
+        self.models.get_ids(model_id, &[id]).pop()
+    }
 
-            // send the query to the server
-            /* if let Ok(Message::Response(models)) = crate::SOCKET.rpc(Message::Query(query)).await {
-                self.models.update(query.model_id, models.clone(), vec![]);
+    // The model disappears from the cached queries, without asking them again
+    pub fn remove_model(&mut self, model_id: ModelId, id: ModelKey) {
+        self.models.remove(model_id, vec![id]);
+    }
 
-                return Ok(models);
-            } */
-        }
+    // A model changed, its cached queries see the change
+    pub fn update_model(&mut self, model: M) {
+        self.models.update(vec![model]);
+    }
+
+    // Models were created or deleted, the queries about them must be asked again
+    pub fn invalidate(&mut self, model_id: ModelId, deleted_ids: Vec<ModelKey>) {
+        self.models.remove(model_id, deleted_ids);
+        self.remove_model_queries(model_id);
+    }
+
+    // The server pushed a change of a watched query of the model: the rows it sent are the latest ones,
+    // and rows may have been created or deleted, so the queries of the model are asked again
+    pub fn apply_watch_event(&mut self, model_id: ModelId, models: Vec<M>) {
+        self.models.update(models);
+        self.remove_model_queries(model_id);
+    }
+
+    pub fn generation(&self, model_id: ModelId) -> u64 {
+        self.generations.get(&model_id).copied().unwrap_or(0)
+    }
+
+    fn remove_model_queries(&mut self, model_id: ModelId) {
+        self.query.remove_model_queries(model_id);
+
+        *self.generations.entry(model_id).or_default() += 1;
+    }
+}
+
+// Returns the results of the query from the cache, or from the given fetch.
+// Identical queries made while one is already sent to the server wait for the same response.
+pub async fn cached_query<M, T, F>(
+    cache: &'static LocalKey<RefCell<Cache<M>>>,
+    query: Query,
+    fetch: F,
+) -> Result<Vec<T>, DbError>
+where
+    M: CacheModel,
+    T: Into<M> + TryFrom<M>,
+    F: Future<Output = Result<Vec<T>, DbError>> + 'static,
+{
+    let query_hash = query.calc_hash();
+    let model_id = query.model_id;
 
-        Err(())
+    let convert = |models: Vec<M>| {
+        models
+            .into_iter()
+            .filter_map(|model| T::try_from(model).ok())
+            .collect::<Vec<_>>()
+    };
+
+    if let Some(models) = cache.with(|cache| cache.borrow().get(&query_hash)) {
+        return Ok(convert(models));
     }
+
+    // A query sent before the last invalidation is not waited for, it is sent again
+    let (generation, pending) = cache.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        let generation = cache.generation(model_id);
+
+        match cache.pending.get(&query_hash) {
+            Some((sent_in, pending)) if *sent_in == generation => (generation, pending.clone()),
+            _ => {
+                let pending = fetch
+                    .map(|res| res.map(|models| models.into_iter().map(Into::into).collect()))
+                    .boxed_local()
+                    .shared();
+
+                cache
+                    .pending
+                    .insert(query_hash, (generation, pending.clone()));
+
+                (generation, pending)
+            }
+        }
+    });
+
+    let res = pending.await;
+
+    cache.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        // A newer identical query may be waiting already
+        if let Some((sent_in, _)) = cache.pending.get(&query_hash) {
+            if *sent_in == generation {
+                cache.pending.remove(&query_hash);
+            }
+        }
+
+        // The answer may be older than a change seen since
+        if cache.generation(model_id) != generation {
+            return;
+        }
+
+        if let Ok(models) = &res {
+            cache.handle_message(Message::Query(query));
+            cache.handle_message(Message::Response(query_hash, models.clone()));
+        }
+    });
+
+    res.map(convert)
 }
 
+#[derive(Default)]
 pub struct ComponentQueryBinder {
     bound: BTreeMap<QueryHash, BTreeSet<ComponentId>>,
 }
@@ -139,43 +305,60 @@ impl ComponentQueryBinder {
     }
 }
 
-pub struct ModelCache {
-    models: BTreeMap<ModelId, BTreeMap<i32, Model>>,
+pub struct ModelCache<M> {
+    models: BTreeMap<ModelId, BTreeMap<ModelKey, M>>,
 }
 
-impl ModelCache {
-    pub fn update(&mut self, model_id: ModelId, models: Vec<Model>, deleted_ids: Vec<i32>) {
-        self.models
-            .entry(model_id)
-            .or_insert(BTreeMap::new())
-            .extend(
-                models
-                    .into_iter()
-                    .map(|model| (model.id(), model))
-                    .collect::<Vec<_>>(),
-            );
-
-        deleted_ids.into_iter().for_each(|id| {
+impl<M> Default for ModelCache<M> {
+    fn default() -> Self {
+        Self {
+            models: BTreeMap::new(),
+        }
+    }
+}
+
+impl<M: CacheModel> ModelCache<M> {
+    pub fn update(&mut self, models: Vec<M>) {
+        for model in models {
             self.models
-                .entry(model_id)
+                .entry(model.model_id())
                 .or_insert(BTreeMap::new())
-                .remove(&id);
-        });
+                .insert(model.id(), model);
+        }
     }
 
-    pub fn get(&self, model_id: ModelId) -> Option<Vec<Model>> {
+    pub fn remove(&mut self, model_id: ModelId, deleted_ids: Vec<ModelKey>) {
+        if let Some(models) = self.models.get_mut(&model_id) {
+            deleted_ids.into_iter().for_each(|id| {
+                models.remove(&id);
+            });
+        }
+    }
+
+    pub fn get(&self, model_id: ModelId) -> Option<Vec<M>> {
         self.models.get(&model_id).map(|models| {
             models
                 .iter()
                 .map(|(_, model)| model.clone())
-                .collect::<Vec<Model>>()
+                .collect::<Vec<M>>()
         })
     }
+
+    // The models with the given ids, in the same order
+    pub fn get_ids(&self, model_id: ModelId, ids: &[ModelKey]) -> Vec<M> {
+        self.models
+            .get(&model_id)
+            .map(|models| ids.iter().filter_map(|id| models.get(id).cloned()).collect())
+            .unwrap_or_default()
+    }
 }
 
+#[derive(Default)]
 pub struct QueryCache {
     queries: BTreeMap<QueryHash, Query>,
-    cache: BTreeMap<QueryHash, BTreeSet<i32>>, // store the ids of the models
+    cache: BTreeMap<QueryHash, Vec<ModelKey>>, // store the ids of the models, in the order of the query
+    // When the server answered, in milliseconds since the epoch
+    answered_at: BTreeMap<QueryHash, f64>,
 }
 
 impl QueryCache {
@@ -183,11 +366,51 @@ impl QueryCache {
         self.queries.insert(query.calc_hash(), query);
     }
 
-    pub fn insert_ids(&mut self, query_hash: QueryHash, ids: BTreeSet<i32>) {
-        self.cache
-            .entry(query_hash)
-            .or_insert(BTreeSet::new())
-            .extend(ids.clone());
+    pub fn get_query(&self, query_hash: &QueryHash) -> Option<&Query> {
+        self.queries.get(query_hash)
+    }
+
+    pub fn set_ids(&mut self, query_hash: QueryHash, ids: Vec<ModelKey>) {
+        self.cache.insert(query_hash, ids);
+        self.answered_at.insert(query_hash, js_sys::Date::now());
+    }
+
+    // How long ago the server answered the query
+    pub fn age(&self, query_hash: &QueryHash) -> Option<Duration> {
+        let answered_at = self.answered_at.get(query_hash)?;
+
+        Some(Duration::from_millis((js_sys::Date::now() - answered_at).max(0.0) as u64))
+    }
+
+    pub fn insert_ids(&mut self, query_hash: QueryHash, ids: Vec<ModelKey>) {
+        let cached = self.cache.entry(query_hash).or_insert(vec![]);
+
+        for id in ids {
+            if !cached.contains(&id) {
+                cached.push(id);
+            }
+        }
+    }
+
+    pub fn remove_ids(&mut self, query_hash: QueryHash, ids: &[ModelKey]) {
+        if let Some(cached) = self.cache.get_mut(&query_hash) {
+            cached.retain(|id| !ids.contains(id));
+        }
+    }
+
+    pub fn remove_model_queries(&mut self, model_id: ModelId) {
+        let removed = self
+            .queries
+            .iter()
+            .filter(|(_, query)| query.model_id == model_id)
+            .map(|(query_hash, _)| *query_hash)
+            .collect::<Vec<_>>();
+
+        for query_hash in removed {
+            self.queries.remove(&query_hash);
+            self.cache.remove(&query_hash);
+            self.answered_at.remove(&query_hash);
+        }
     }
 
     pub fn contains(&self, query_hash: &QueryHash) -> bool {
@@ -195,7 +418,7 @@ impl QueryCache {
     }
 
     // get the watched models' ids for the given query
-    pub fn get_query_ids(&self, query_hash: &QueryHash) -> Option<&BTreeSet<i32>> {
+    pub fn get_query_ids(&self, query_hash: &QueryHash) -> Option<&Vec<ModelKey>> {
         self.cache.get(query_hash)
     }
 }
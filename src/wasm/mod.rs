@@ -1,6 +1,10 @@
 mod app;
+mod auth;
 pub mod cache;
 mod log_macro;
 pub mod prelude;
+pub mod queue;
+pub mod rooms;
 mod socket;
 pub mod utils;
+pub mod watch;
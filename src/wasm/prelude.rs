@@ -6,7 +6,13 @@ pub use tokio;
 
 pub use crate::wasm::*;
 
+pub use crate::wasm::app::redraw;
+pub use crate::wasm::cache::{cached_query, Cache, CacheModel, ModelId, Query};
+pub use crate::wasm::auth::{clear_session, store_session};
+pub use crate::wasm::queue::{on_conflict, Conflict};
+pub use crate::wasm::rooms::{handle_room_event, on_broadcast};
 pub use crate::wasm::socket::*;
 pub use crate::wasm::utils::*;
+pub use crate::wasm::watch::{handle_push, handle_watch_event, watched};
 pub use futures;
 pub use wasm_bindgen_futures::spawn_local;
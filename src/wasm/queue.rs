@@ -0,0 +1,217 @@
+use std::{future::Future, pin::Pin};
+
+use futures::lock::Mutex;
+use js_sys::{Array, Promise, Uint8Array};
+use lazy_static::lazy_static;
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbOpenDbRequest, IdbRequest, IdbTransactionMode};
+
+use crate::prelude::*;
+
+const DB_NAME: &str = "comet";
+// One record per queued mutation, by id
+const STORE: &str = "queue";
+
+// A mutation made while offline, waiting to be sent to the server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueuedRpc {
+    pub id: u64,
+    // Kept when sent again, so the server doesn't run a mutation twice
+    pub request_id: u64,
+    // The serialized query
+    pub msg: Vec<u8>,
+}
+
+// A queued mutation that the server rejected once replayed
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    // The debug representation of the query
+    pub query: String,
+    pub error: String,
+}
+
+type ConflictHandler = Rc<dyn Fn(Conflict) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+thread_local! {
+    static HANDLERS: RefCell<Vec<ConflictHandler>> = RefCell::new(vec![]);
+    // Opened on the first use of the queue
+    static DB: RefCell<Option<IdbDatabase>> = const { RefCell::new(None) };
+}
+
+lazy_static! {
+    // The queue is read and written as a whole, one change at a time
+    static ref QUEUE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// Register a handler called with every queued mutation rejected by the server once replayed,
+// including the ones queued before the page was reloaded.
+// The application is redrawn once all the handlers are done.
+pub fn on_conflict<F, Fut>(f: F)
+where
+    F: Fn(Conflict) -> Fut + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let handler: ConflictHandler =
+        Rc::new(move |conflict| Box::pin(f(conflict)) as Pin<Box<dyn Future<Output = ()>>>);
+
+    HANDLERS.with(|handlers| handlers.borrow_mut().push(handler));
+}
+
+pub fn handle_conflict(conflict: Conflict) {
+    let handlers = HANDLERS.with(|handlers| handlers.borrow().clone());
+
+    spawn_local(async move {
+        for handler in handlers {
+            handler(conflict.clone()).await;
+        }
+
+        redraw();
+    });
+}
+
+// Resolves once the request succeeded or failed
+async fn wait(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+
+    JsFuture::from(promise).await?;
+
+    request.result()
+}
+
+// Always called with the queue locked, the database is only opened once
+async fn open() -> Result<IdbDatabase, JsValue> {
+    if let Some(db) = DB.with(|db| db.borrow().clone()) {
+        return Ok(db);
+    }
+
+    let request: IdbOpenDbRequest = web_sys::window()
+        .unwrap()
+        .indexed_db()?
+        .ok_or_else(|| JsValue::from_str("IndexedDB is not available"))?
+        .open_with_u32(DB_NAME, 1)?;
+
+    let on_upgrade = Closure::once(move |event: web_sys::Event| {
+        let db: IdbDatabase = event
+            .target()
+            .unwrap()
+            .unchecked_into::<IdbOpenDbRequest>()
+            .result()
+            .unwrap()
+            .unchecked_into();
+
+        db.create_object_store(STORE).unwrap();
+    });
+
+    request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+
+    let db: IdbDatabase = wait(&request).await?.unchecked_into();
+
+    request.set_onupgradeneeded(None);
+
+    DB.with(|cached| *cached.borrow_mut() = Some(db.clone()));
+
+    Ok(db)
+}
+
+fn key(id: u64) -> JsValue {
+    JsValue::from_f64(id as f64)
+}
+
+// The queued mutations, in order.
+// A record that can't be decoded, like one written by another version of the app, is logged and dropped
+async fn load() -> Result<Vec<QueuedRpc>, JsValue> {
+    let db = open().await?;
+
+    let store = db.transaction_with_str(STORE)?.object_store(STORE)?;
+
+    // Both requests are waited for at once, the transaction ends with the last one
+    let (keys, values) = (store.get_all_keys()?, store.get_all()?);
+    let (keys, values) = futures::join!(wait(&keys), wait(&values));
+    let (keys, values): (Array, Array) = (keys?.unchecked_into(), values?.unchecked_into());
+
+    let mut queue = vec![];
+    let mut unreadable = vec![];
+
+    for (key, value) in keys.iter().zip(values.iter()) {
+        match serde_cbor::from_slice::<QueuedRpc>(&Uint8Array::new(&value).to_vec()) {
+            Ok(queued) => queue.push(queued),
+            Err(e) => {
+                console_log!("Dropping an unreadable queued mutation: {}", e);
+
+                unreadable.push(key);
+            }
+        }
+    }
+
+    if !unreadable.is_empty() {
+        let store = db
+            .transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?
+            .object_store(STORE)?;
+
+        for key in unreadable {
+            store.delete(&key)?;
+        }
+    }
+
+    Ok(queue)
+}
+
+// Append a mutation at the end of the queue, and return its id
+pub async fn enqueue(request_id: u64, msg: Vec<u8>) -> Result<u64, JsValue> {
+    let _lock = QUEUE_LOCK.lock().await;
+
+    let id = load()
+        .await?
+        .last()
+        .map(|queued| queued.id + 1)
+        .unwrap_or(0);
+
+    let queued = QueuedRpc {
+        id,
+        request_id,
+        msg,
+    };
+
+    let bytes = serde_cbor::to_vec(&queued).unwrap();
+
+    let request = open()
+        .await?
+        .transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?
+        .object_store(STORE)?
+        .put_with_key(&Uint8Array::from(&bytes[..]), &key(id))?;
+
+    wait(&request).await?;
+
+    Ok(id)
+}
+
+// The oldest queued mutation
+pub async fn first() -> Result<Option<QueuedRpc>, JsValue> {
+    let _lock = QUEUE_LOCK.lock().await;
+
+    Ok(load().await?.into_iter().next())
+}
+
+pub async fn remove(id: u64) -> Result<(), JsValue> {
+    let _lock = QUEUE_LOCK.lock().await;
+
+    let request = open()
+        .await?
+        .transaction_with_str_and_mode(STORE, IdbTransactionMode::Readwrite)?
+        .object_store(STORE)?
+        .delete(&key(id))?;
+
+    wait(&request).await?;
+
+    Ok(())
+}
+
+pub async fn is_empty() -> bool {
+    let _lock = QUEUE_LOCK.lock().await;
+
+    load().await.map(|queue| queue.is_empty()).unwrap_or(true)
+}
@@ -0,0 +1,52 @@
+use std::{collections::HashMap, future::Future, pin::Pin};
+
+use serde::de::DeserializeOwned;
+
+use crate::prelude::*;
+
+type RoomHandler = Rc<dyn Fn(&RoomEvent) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+thread_local! {
+    static HANDLERS: RefCell<HashMap<String, Vec<RoomHandler>>> = RefCell::new(HashMap::new());
+}
+
+// Register a handler called with every payload broadcasted to the given room.
+// The application is redrawn once all the handlers of the room are done.
+pub fn on_broadcast<T, F, Fut>(room: &str, f: F)
+where
+    T: DeserializeOwned + 'static,
+    F: Fn(T) -> Fut + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let handler: RoomHandler = Rc::new(move |event: &RoomEvent| match event.decode() {
+        Ok(payload) => Box::pin(f(payload)) as Pin<Box<dyn Future<Output = ()>>>,
+        // Not a `T`, the handler is skipped
+        Err(e) => {
+            console_log!("Bad payload in room {}: {}", event.room, e);
+
+            Box::pin(async {})
+        }
+    });
+
+    HANDLERS.with(|handlers| {
+        handlers
+            .borrow_mut()
+            .entry(room.to_string())
+            .or_default()
+            .push(handler);
+    });
+}
+
+pub fn handle_room_event(event: RoomEvent) {
+    let handlers = HANDLERS.with(|handlers| handlers.borrow().get(&event.room).cloned());
+
+    if let Some(handlers) = handlers {
+        spawn_local(async move {
+            for handler in handlers {
+                handler(&event).await;
+            }
+
+            redraw();
+        });
+    }
+}
@@ -1,6 +1,5 @@
 use {
-    futures::stream::StreamExt, pharos::*, wasm_bindgen::UnwrapThrowExt,
-    wasm_bindgen_futures::spawn_local, ws_stream_wasm::*,
+    futures::stream::StreamExt, pharos::*, wasm_bindgen_futures::spawn_local, ws_stream_wasm::*,
 };
 
 use futures::{
@@ -9,19 +8,143 @@ use futures::{
 };
 use serde::{de::DeserializeOwned, Serialize};
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use crate::prelude::*;
 
 use crate::ProtoTrait;
 
-use crate::Message;
+use crate::{Message, Push, PUSH_REQUEST_ID};
 
-pub struct Socket<P: ProtoTrait + 'static + Serialize + DeserializeOwned + Debug> {
+use super::queue::{self, Conflict};
+
+type PendingRequests = Arc<RwLock<HashMap<u64, futures::channel::oneshot::Sender<Message>>>>;
+type QueueWaiters = Arc<RwLock<HashMap<u64, futures::channel::oneshot::Sender<Message>>>>;
+
+// Delay before trying to connect again, in milliseconds
+const RECONNECT_DELAY: i32 = 1000;
+
+// Random, so the ids stay unique across page reloads for the queued mutations the server deduplicates
+fn new_request_id() -> u64 {
+    let half = || (js_sys::Math::random() * u32::MAX as f64) as u64;
+
+    ((half() << 32) | half()) % PUSH_REQUEST_ID
+}
+
+enum SocketEvent {
+    Out(Message),
+    In(WsMessage),
+    Closed,
+}
+
+// What both the socket and its connection loop need
+#[derive(Clone)]
+struct Link {
     tx: UnboundedSender<Message>,
+    // Given by the server on every connection, to get the rooms back after a reconnection
+    resume_token: Arc<RwLock<Option<String>>>,
+    pending_requests: PendingRequests,
+    connected: Arc<AtomicBool>,
+    // Set while the queued mutations are sent, the new ones go after them
+    replaying: Arc<AtomicBool>,
+    queue_waiters: QueueWaiters,
+}
+
+impl Link {
+    // Send the query and wait for its response
+    async fn request(&self, msg: Vec<u8>) -> Message {
+        self.request_with_id(new_request_id(), msg).await
+    }
+
+    // A query still waiting when the connection is lost is sent again once reconnected, with the same id:
+    // the server runs it only once and gives the same response again
+    async fn request_with_id(&self, request_id: u64, msg: Vec<u8>) -> Message {
+        loop {
+            let (tx, rx) = futures::channel::oneshot::channel::<Message>();
+
+            self.pending_requests.write().await.insert(request_id, tx);
+
+            self.tx
+                .unbounded_send(Message {
+                    request_id,
+                    msg: msg.clone(),
+                    acks: vec![],
+                })
+                .unwrap();
+
+            if let Ok(response) = rx.await {
+                return response;
+            }
+        }
+    }
+
+    // Send the queued mutations in order, until there is none left
+    async fn replay<P: ProtoTrait + Serialize + DeserializeOwned + Debug>(self) {
+        loop {
+            if self.replaying.swap(true, Ordering::SeqCst) {
+                return;
+            }
+
+            while self.connected.load(Ordering::SeqCst) {
+                let queued = match queue::first().await {
+                    Ok(Some(queued)) => queued,
+                    _ => break,
+                };
+
+                let response = self
+                    .request_with_id(queued.request_id, queued.msg.clone())
+                    .await;
+
+                let _ = queue::remove(queued.id).await;
+
+                let error = match P::from_bytes(&response.msg) {
+                    Ok(response) => response.error(),
+                    Err(e) => Some(format!("Malformed response: {}", e)),
+                };
+
+                if let Some(error) = error {
+                    queue::handle_conflict(Conflict {
+                        query: P::from_bytes(&queued.msg)
+                            .map(|query| format!("{:?}", query))
+                            .unwrap_or_default(),
+                        error,
+                    });
+                }
+
+                // The caller is still waiting if the page was not reloaded
+                if let Some(waiter) = self.queue_waiters.write().await.remove(&queued.id) {
+                    let _ = waiter.send(response);
+                }
+            }
+
+            self.replaying.store(false, Ordering::SeqCst);
+
+            // A mutation may have been queued while the last one was sent
+            if !self.connected.load(Ordering::SeqCst) || queue::is_empty().await {
+                return;
+            }
+        }
+    }
+}
+
+async fn sleep(ms: i32) {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, ms)
+            .unwrap();
+    });
+
+    let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+}
+
+pub struct Socket<P: ProtoTrait + 'static + Serialize + DeserializeOwned + Debug> {
+    link: Link,
     rx: Option<UnboundedReceiver<Message>>,
-    next_request_id: u64,
-    pending_requests: Arc<RwLock<HashMap<u64, futures::channel::oneshot::Sender<Message>>>>,
     _phantom: std::marker::PhantomData<P>,
 }
 
@@ -29,98 +152,164 @@ impl<P: ProtoTrait + 'static + Serialize + DeserializeOwned + Debug> Socket<P>
 where
     Self: 'static,
 {
-    pub fn new(
-        tx: UnboundedSender<Message>,
-        rx: Option<UnboundedReceiver<Message>>,
-        pending_requests: Arc<RwLock<HashMap<u64, futures::channel::oneshot::Sender<Message>>>>,
-    ) -> Self {
+    // Returns right away, the connection is (re)established in the background
+    pub async fn connect(url: String) -> Self {
+        // in to the socket or out of the socket
+        let (in_tx, in_rx) = futures::channel::mpsc::unbounded::<Message>();
+        let (out_tx, out_rx) = futures::channel::mpsc::unbounded();
+
+        let link = Link {
+            tx: in_tx,
+            resume_token: Arc::new(RwLock::new(None)),
+            pending_requests: Arc::new(RwLock::new(HashMap::new())),
+            connected: Arc::new(AtomicBool::new(false)),
+            replaying: Arc::new(AtomicBool::new(false)),
+            queue_waiters: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        spawn_local(Self::run(url, link.clone(), in_rx, out_tx));
+
         Self {
-            tx,
-            rx,
-            next_request_id: 0,
-            pending_requests,
+            link,
+            rx: Some(out_rx),
             _phantom: std::marker::PhantomData,
         }
     }
 
-    pub async fn connect(url: String) -> Self {
-        // in to the socket or out of the socket
-        let (in_tx, mut in_rx) = futures::channel::mpsc::unbounded::<Message>();
-        let (mut out_tx, out_rx) = futures::channel::mpsc::unbounded();
+    async fn run(
+        url: String,
+        link: Link,
+        mut in_rx: UnboundedReceiver<Message>,
+        mut out_tx: UnboundedSender<Message>,
+    ) {
+        let mut reconnecting = false;
+
+        loop {
+            let full_url = match link.resume_token.read().await.as_ref() {
+                Some(token) => format!("{}?resume={}", url, token),
+                None => url.clone(),
+            };
+
+            let (mut ws, wsio) = match WsMeta::connect(full_url, None).await {
+                Ok(connection) => connection,
+                Err(_) => {
+                    sleep(RECONNECT_DELAY).await;
+
+                    continue;
+                }
+            };
+
+            let _evts = ws.observe(ObserveConfig::default()).await.unwrap();
 
-        let (mut ws, wsio) = WsMeta::connect(url, None)
-            .await
-            .expect_throw("assume the connection succeeds");
+            let (mut ws_tx, ws_rx) = wsio.split();
 
-        let _evts = ws.observe(ObserveConfig::default()).await.unwrap();
+            link.connected.store(true, Ordering::SeqCst);
 
-        let (mut ws_tx, mut ws_rx) = wsio.split();
+            spawn_local(link.clone().replay::<P>());
 
-        let input_loop = async move {
-            while let Some(msg) = in_rx.next().await {
-                ws_tx.send(WsMessage::Binary(msg.to_bytes())).await.unwrap();
+            // The watched queries may have changed while offline
+            if reconnecting {
+                super::watch::rewatch_all();
             }
-        };
 
-        spawn_local(input_loop);
+            reconnecting = true;
+
+            let closed = futures::stream::once(futures::future::ready(SocketEvent::Closed));
+
+            let mut events = futures::stream::select(
+                in_rx.by_ref().map(SocketEvent::Out),
+                ws_rx.map(SocketEvent::In).chain(closed),
+            );
+
+            while let Some(event) = events.next().await {
+                match event {
+                    SocketEvent::Out(msg) => {
+                        if ws_tx.send(WsMessage::Binary(msg.to_bytes())).await.is_err() {
+                            break;
+                        }
+                    }
+                    SocketEvent::In(WsMessage::Binary(blob)) => {
+                        let msg = match Message::from_bytes(&blob) {
+                            Ok(msg) => msg,
+                            // bad message
+                            Err(_) => continue,
+                        };
 
-        let pending_requests = Arc::new(RwLock::new(HashMap::new()));
-        let pending_requests2 = Arc::clone(&pending_requests);
+                        if msg.is_push() {
+                            if let Ok(Push::Resume(token)) = Push::from_bytes(&msg.msg) {
+                                link.resume_token.write().await.replace(token);
 
-        let output_loop = async move {
-            while let Some(msg) = ws_rx.next().await {
-                if let WsMessage::Binary(blob) = msg {
-                    let msg = Message::from_bytes(&blob);
+                                continue;
+                            }
+                        }
 
-                    if pending_requests2.read().await.contains_key(&msg.request_id) {
-                        let tx: futures::channel::oneshot::Sender<Message> = pending_requests2
-                            .write()
-                            .await
-                            .remove(&msg.request_id)
-                            .unwrap();
+                        let pending = link.pending_requests.write().await.remove(&msg.request_id);
 
-                        tx.send(msg).unwrap();
-                    } else {
-                        out_tx.send(msg).await.unwrap();
+                        if let Some(tx) = pending {
+                            let ack = Message::ack(vec![msg.request_id]);
+
+                            let _ = tx.send(msg);
+
+                            // The server keeps the response until then, in case the query is sent again.
+                            // A lost connection is seen by the next event
+                            let _ = ws_tx.send(WsMessage::Binary(ack.to_bytes())).await;
+                        } else {
+                            out_tx.send(msg).await.unwrap();
+                        }
                     }
-                } else {
                     // bad message type
+                    SocketEvent::In(_) => (),
+                    SocketEvent::Closed => break,
                 }
             }
-        };
 
-        spawn_local(output_loop);
+            link.connected.store(false, Ordering::SeqCst);
+
+            // The server forgot about them, they are sent again once reconnected
+            link.pending_requests.write().await.clear();
 
-        Self::new(in_tx, Some(out_rx), pending_requests)
+            sleep(RECONNECT_DELAY).await;
+        }
     }
 
-    pub async fn rpc(&mut self, packet: P) -> P {
-        let request_id = self.next_request_id;
-        self.next_request_id += 1;
+    pub fn is_connected(&self) -> bool {
+        self.link.connected.load(Ordering::SeqCst)
+    }
 
-        let (tx, rx) = futures::channel::oneshot::channel::<Message>();
-        let (future, _handle) =
-            futures::future::abortable(async move { P::from_bytes(&rx.await.unwrap().msg) });
+    // Only needs a shared reference, so concurrent RPCs don't wait for each other
+    pub async fn rpc(&self, packet: P) -> Result<P, serde_cbor::Error> {
+        P::from_bytes(&self.link.request(packet.to_bytes()).await.msg)
+    }
 
-        // if timeoug then abort the handle
-        /* let timeout = async move {
-            // tokio::time::delay_for(std::time::Duration::from_secs(10)).await;
-            handle.abort();
-        }; */
+    // For mutations: while offline, or while older ones are waiting,
+    // the query is persisted and sent in order once reconnected
+    pub async fn rpc_queued(&self, packet: P) -> Result<P, serde_cbor::Error> {
+        let is_ready = self.is_connected()
+            && !self.link.replaying.load(Ordering::SeqCst)
+            && queue::is_empty().await;
 
-        self.pending_requests.write().await.insert(request_id, tx);
+        if is_ready {
+            return self.rpc(packet).await;
+        }
 
-        let msg = Message {
-            request_id,
-            msg: packet.to_bytes(),
+        let id = match queue::enqueue(new_request_id(), packet.to_bytes()).await {
+            Ok(id) => id,
+            // Without IndexedDB the query waits in memory
+            Err(_) => return self.rpc(packet).await,
         };
 
-        self.tx.send(msg).await.unwrap();
+        let (tx, rx) = futures::channel::oneshot::channel::<Message>();
+
+        self.link.queue_waiters.write().await.insert(id, tx);
+
+        if self.is_connected() {
+            spawn_local(self.link.clone().replay::<P>());
+        }
 
-        future.await.unwrap()
+        P::from_bytes(&rx.await.unwrap().msg)
     }
 
-    pub async fn send(&mut self, packet: P) -> P {
+    pub async fn send(&self, packet: P) -> Result<P, serde_cbor::Error> {
         self.rpc(packet).await
     }
 
@@ -0,0 +1,151 @@
+use std::{cell::Cell, collections::HashMap, future::Future};
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+
+use crate::prelude::*;
+
+type WatchHandler = Rc<dyn Fn(&WatchEvent)>;
+
+// Runs the watch RPC again, and gives all its results as a change
+type Rewatch = Rc<dyn Fn() -> LocalBoxFuture<'static, Option<WatchEvent>>>;
+
+thread_local! {
+    // The handlers of each watched query, by handler id
+    static HANDLERS: RefCell<HashMap<QueryHash, Vec<(u64, WatchHandler)>>> = RefCell::new(HashMap::new());
+    static NEXT_HANDLER_ID: Cell<u64> = const { Cell::new(0) };
+    // The server forgets the watches of a lost connection, they are asked again once reconnected
+    static REWATCHES: RefCell<HashMap<QueryHash, Rewatch>> = RefCell::new(HashMap::new());
+}
+
+// Keep the results of a watched query up to date with the changes pushed by the server,
+// `on_event` is also given every change.
+// `rewatch` subscribes again after a reconnection, and returns the current results.
+// Once they are dropped, and no other results watch the same query, `unwatch` is called
+pub fn watched<T, R, Fut, E, F>(
+    query_hash: QueryHash,
+    models: Vec<(ModelKey, T)>,
+    rewatch: R,
+    on_event: E,
+    unwatch: F,
+) -> Watched<T>
+where
+    T: Serialize + DeserializeOwned + 'static,
+    R: Fn() -> Fut + 'static,
+    Fut: Future<Output = Result<Vec<(ModelKey, T)>, DbError>> + 'static,
+    E: Fn(&WatchEvent) + 'static,
+    F: FnOnce() + Send + Sync + 'static,
+{
+    let watched = Watched::new(query_hash, models);
+
+    let rewatch: Rewatch = Rc::new(move || {
+        rewatch()
+            .map(move |res| {
+                let models = res.ok()?;
+
+                // The results replace the previous ones as a whole
+                Some(WatchEvent {
+                    query_hash,
+                    order: models.iter().map(|(id, _)| id.clone()).collect(),
+                    models: models
+                        .iter()
+                        .map(|(id, model)| (id.clone(), serde_cbor::to_vec(model).unwrap()))
+                        .collect(),
+                    deleted_ids: vec![],
+                })
+            })
+            .boxed_local()
+    });
+
+    REWATCHES.with(|rewatches| {
+        rewatches.borrow_mut().entry(query_hash).or_insert(rewatch);
+    });
+
+    // The handler has no subscription, it would keep itself alive
+    let handler: WatchHandler = {
+        let watched = watched.clone();
+
+        Rc::new(move |event: &WatchEvent| {
+            if let Err(e) = watched.apply(event) {
+                console_log!("Bad change of a watched query: {}", e);
+
+                return;
+            }
+
+            on_event(event);
+        })
+    };
+
+    let handler_id = NEXT_HANDLER_ID.with(|next| next.replace(next.get() + 1));
+
+    HANDLERS.with(|handlers| {
+        handlers
+            .borrow_mut()
+            .entry(query_hash)
+            .or_insert_with(Vec::new)
+            .push((handler_id, handler));
+    });
+
+    watched.subscribed(Subscription::new(move || {
+        let last = HANDLERS.with(|handlers| {
+            let mut handlers = handlers.borrow_mut();
+
+            let remaining = match handlers.get_mut(&query_hash) {
+                Some(remaining) => remaining,
+                None => return false,
+            };
+
+            remaining.retain(|(id, _)| *id != handler_id);
+
+            if remaining.is_empty() {
+                handlers.remove(&query_hash);
+
+                REWATCHES.with(|rewatches| rewatches.borrow_mut().remove(&query_hash));
+
+                true
+            } else {
+                false
+            }
+        });
+
+        if last {
+            unwatch();
+        }
+    }))
+}
+
+pub fn handle_watch_event(event: WatchEvent) {
+    let handlers = HANDLERS.with(|handlers| handlers.borrow().get(&event.query_hash).cloned());
+
+    if let Some(handlers) = handlers {
+        for (_, handler) in handlers {
+            handler(&event);
+        }
+
+        redraw();
+    }
+}
+
+// Watch again every query still watched, after a reconnection
+pub(crate) fn rewatch_all() {
+    let rewatches =
+        REWATCHES.with(|rewatches| rewatches.borrow().values().cloned().collect::<Vec<_>>());
+
+    for rewatch in rewatches {
+        spawn_local(async move {
+            if let Some(event) = rewatch().await {
+                handle_watch_event(event);
+            }
+        });
+    }
+}
+
+// Dispatch a message pushed by the server
+pub fn handle_push(push: Push) {
+    match push {
+        Push::Room(event) => handle_room_event(event),
+        Push::Event(event) => handle_watch_event(event),
+        // Kept by the socket
+        Push::Resume(_) => (),
+    }
+}
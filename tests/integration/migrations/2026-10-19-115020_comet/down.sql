@@ -0,0 +1,9 @@
+CREATE TABLE comet_new_todos (
+    id INTEGER PRIMARY KEY,
+    owner_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    completed BOOLEAN NOT NULL
+);
+INSERT INTO comet_new_todos (id, owner_id, title, completed) SELECT id, owner_id, title, completed FROM todos;
+DROP TABLE todos;
+ALTER TABLE comet_new_todos RENAME TO todos;
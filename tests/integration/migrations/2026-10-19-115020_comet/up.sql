@@ -0,0 +1,10 @@
+CREATE TABLE comet_new_todos (
+    id INTEGER PRIMARY KEY,
+    owner_id INTEGER NOT NULL,
+    title VARCHAR(40) NOT NULL,
+    completed BOOLEAN NOT NULL,
+    priority INTEGER NOT NULL DEFAULT 3
+);
+INSERT INTO comet_new_todos (id, owner_id, title, completed) SELECT id, owner_id, title, completed FROM todos;
+DROP TABLE todos;
+ALTER TABLE comet_new_todos RENAME TO todos;
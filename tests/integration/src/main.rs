@@ -0,0 +1,338 @@
+use comet::prelude::*;
+
+pub struct TodoPolicy;
+
+// Only the logged users can list the todos, and only the owner of a todo can change it, without giving it away
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait]
+impl Policy<Todo> for TodoPolicy {
+    async fn can_list(context: &RequestContext) -> bool {
+        context.user_id().is_some()
+    }
+
+    async fn can_update(context: &RequestContext, _id: &i32, stored: &Todo, sent: &Todo) -> bool {
+        context.user_id() == Some(stored.owner_id) && sent.owner_id == stored.owner_id
+    }
+
+    async fn can_delete(context: &RequestContext, _id: &i32, stored: &Todo) -> bool {
+        context.user_id() == Some(stored.owner_id)
+    }
+}
+
+#[model(policy = TodoPolicy)]
+#[derive(Default, Debug)]
+pub struct Todo {
+    pub owner_id: i32,
+    #[max_len(40)]
+    pub title: String,
+    pub completed: bool,
+    #[default(3)]
+    pub priority: i32,
+}
+
+#[sql]
+impl Todo {
+    #[watch]
+    pub async fn db_by_owner(owner: i32) -> Vec<Todo> {
+        use crate::schema::todos;
+
+        todos::table.filter(todos::owner_id.eq(owner))
+    }
+
+    // Depends on the calling user, watched for each session apart
+    #[watch]
+    pub async fn db_mine() -> Vec<Todo> {
+        use crate::schema::todos;
+
+        let owner = request_context().user_id().unwrap_or_default();
+
+        todos::table.filter(todos::owner_id.eq(owner))
+    }
+
+    pub async fn db_titled(title: String) -> Vec<Todo> {
+        use crate::schema::todos;
+
+        todos::table.filter(todos::title.eq(title))
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    title: String,
+}
+
+component! {
+    App,
+    div {
+        { self.title }
+    }
+}
+
+comet::auth!();
+
+comet::run!(App::default());
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Once, RwLock};
+
+    use super::*;
+
+    static SETUP: Once = Once::new();
+
+    // A fresh database for each run, with every migration applied
+    fn setup() {
+        SETUP.call_once(|| {
+            use diesel::connection::SimpleConnection;
+
+            let path = std::env::temp_dir().join(format!("comet_tests_{}.db", std::process::id()));
+
+            std::env::set_var("DATABASE_URL", path.to_str().unwrap());
+
+            let mut conn = establish_connection();
+
+            let mut migrations = std::fs::read_dir("migrations")
+                .unwrap()
+                .map(|entry| entry.unwrap().path().join("up.sql"))
+                .filter(|up| up.exists())
+                .collect::<Vec<_>>();
+
+            migrations.sort();
+
+            for up in migrations {
+                conn.batch_execute(&std::fs::read_to_string(up).unwrap())
+                    .unwrap();
+            }
+        });
+    }
+
+    fn context(user_id: Option<i32>) -> RequestContext {
+        RequestContext::new(
+            0,
+            None,
+            "127.0.0.1:0".parse().unwrap(),
+            Default::default(),
+            Arc::new(RwLock::new(user_id)),
+            Arc::new(RwLock::new(None)),
+        )
+    }
+
+    async fn new_todo(owner_id: i32) -> Todo {
+        Todo {
+            id: None,
+            owner_id,
+            title: "Mine".to_string(),
+            completed: false,
+            priority: 1,
+        }
+        .create()
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn policy_judges_the_stored_row() {
+        setup();
+
+        let todo = new_todo(1).await;
+        let id = todo.id.unwrap();
+
+        // Someone else claims the todo by sending it with their own id
+        let mut forged = todo.clone();
+        forged.owner_id = 2;
+
+        assert!(!Todo::__policy_can_update(&context(Some(2)), &id, &forged).await);
+        assert!(!Todo::__policy_can_save(&context(Some(2)), &forged).await);
+        assert!(!Todo::__policy_can_delete(&context(Some(2)), &id).await);
+
+        assert!(Todo::__policy_can_update(&context(Some(1)), &id, &todo).await);
+        assert!(Todo::__policy_can_delete(&context(Some(1)), &id).await);
+
+        // The owner can't give it away either, the sent values are judged too
+        assert!(!Todo::__policy_can_update(&context(Some(1)), &id, &forged).await);
+        assert!(!Todo::__policy_can_save(&context(Some(1)), &forged).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn policy_refuses_missing_rows() {
+        setup();
+
+        let todo = new_todo(1).await;
+
+        Todo::delete(todo.id.unwrap()).await.unwrap();
+
+        assert!(!Todo::__policy_can_update(&context(Some(1)), &todo.id.unwrap(), &todo).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watching_checks_the_list_policy() {
+        setup();
+
+        assert_eq!(Todo::TABLE, "todos");
+
+        assert!(!Todo::__can_watch_db_by_owner(&context(None)).await);
+        assert!(Todo::__can_watch_db_by_owner(&context(Some(1))).await);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn watched_queries_run_for_the_watching_user() {
+        setup();
+
+        new_todo(6).await;
+
+        let (_, mine) = context(Some(6)).scope(Todo::__watch_db_mine()).await.unwrap();
+        let (_, others) = context(Some(7)).scope(Todo::__watch_db_mine()).await.unwrap();
+
+        assert!(!mine.is_empty());
+        assert!(mine.iter().all(|(_, todo)| todo.owner_id == 6));
+        assert!(others.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queries_are_checked_against_the_model() {
+        setup();
+
+        let todo = new_todo(9).await;
+
+        let found = Todo::list_query(Todo::query().filter(todo_columns::owner_id.eq(9)))
+            .await
+            .unwrap();
+
+        assert_eq!(found.iter().map(|todo| todo.id).collect::<Vec<_>>(), vec![todo.id]);
+
+        // Columns and values the model doesn't have, as a forged query would send them
+        let unknown = ModelColumn::<Todo, i32>::new("password");
+        let mistyped = ModelColumn::<Todo, String>::new("priority");
+
+        assert!(Todo::list_query(Todo::query().filter(unknown.eq(1))).await.is_err());
+        assert!(Todo::list_query(Todo::query().order_by(unknown)).await.is_err());
+        assert!(Todo::list_query(Todo::query().filter(mistyped.eq("high".to_string())))
+            .await
+            .is_err());
+
+        assert!(Todo::list_query(Todo::query().limit(MAX_LIMIT + 1)).await.is_err());
+        assert!(Todo::list_paginated(1, MAX_LIMIT + 1).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sql_queries_return_a_result() {
+        setup();
+
+        let todo = new_todo(10).await;
+
+        let found: Result<Vec<Todo>, DbError> = Todo::db_titled(todo.title.clone()).await;
+
+        assert!(found.unwrap().iter().any(|found| found.id == todo.id));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn defaults_fill_the_fields_left_out() {
+        setup();
+
+        let todo = NewTodo {
+            owner_id: 1,
+            title: "Someday".to_string(),
+            completed: false,
+            priority: None,
+        }
+        .create()
+        .await
+        .unwrap();
+
+        assert_eq!(todo.priority, 3);
+        assert_eq!(new_todo(1).await.priority, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn max_len_is_validated() {
+        setup();
+
+        let todo = Todo {
+            title: "x".repeat(41),
+            ..Default::default()
+        };
+
+        match todo.create().await {
+            Err(DbError::Validation(errors)) => assert!(errors.to_string().contains("title")),
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn panicking_transactions_are_rolled_back() {
+        setup();
+
+        let panicked = tokio::spawn(transaction(|| async {
+            new_todo(4).await;
+
+            panic!("In the transaction");
+
+            #[allow(unreachable_code)]
+            Ok::<_, DbError>(())
+        }))
+        .await;
+
+        assert!(panicked.is_err());
+
+        // The connection of the transaction doesn't hold the database locked either
+        new_todo(5).await;
+
+        let owners = with_connection(|conn| {
+            use crate::schema::todos;
+
+            Ok(todos::table
+                .filter(todos::owner_id.eq_any([4, 5]))
+                .select(todos::owner_id)
+                .load::<i32>(conn)?)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(owners, vec![5]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn usernames_are_unique() {
+        setup();
+
+        context(None)
+            .scope(async {
+                assert!(User::auth_register("alice".into(), "secret".into()).await.is_ok());
+
+                assert_eq!(
+                    User::auth_register("alice".into(), "other".into()).await.unwrap_err(),
+                    "Username already taken"
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn expired_sessions_resolve_to_nobody() {
+        setup();
+
+        context(None)
+            .scope(async {
+                use crate::schema::sessions;
+
+                let (user, _grant) = User::auth_register("bob".into(), "secret".into()).await.unwrap();
+                let token = request_context().session_token().unwrap();
+
+                assert_eq!(auth::authenticate(token.clone()).await, user.id);
+
+                let token_given = token.clone();
+                let past = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+
+                with_connection(move |conn| {
+                    Ok(diesel::update(sessions::table.filter(sessions::dsl::token.eq(token_given)))
+                        .set(sessions::dsl::expires_at.eq(past))
+                        .execute(conn)?)
+                })
+                .await
+                .unwrap();
+
+                assert_eq!(auth::authenticate(token).await, None);
+            })
+            .await;
+    }
+}